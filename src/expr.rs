@@ -1,331 +1,2064 @@
-use core::{fmt, iter::Peekable};
+//! The expression engine, independent of the editor.
+//!
+//! The engine needs `std`, so there is no `no_std` build of it. `core` has none of the
+//! floating-point functions like `powf` and `sin` for `f128`, nor a `HashMap` or the clocks behind
+//! [`Environment::deadline`] and `now()`. Everything else is taken from `core` and `alloc`.
+//!
+//! # Grammar
+//!
+//! [`parse_str`] reads a statement by this grammar, in which `*` repeats, `?` is optional, and
+//! `|` separates choices. Whitespace only separates tokens, and zero-width characters are ignored.
+//!
+//! ```text
+//! statement  = (name assignment)* expression
+//! assignment = "=" | "+=" | "-=" | "*=" | "/="
+//! expression = operand (operator operand | argument)*
+//! operand    = number | name | call | "(" expression ")" | ("+" | "-") operand
+//! call       = function argument
+//!            | "now" "(" ")"
+//!            | ("pow" | "pctchange" | "pctof" | "gcd" | "lcm" | "mod" | "sigfig")
+//!              "(" expression "," expression ")"
+//!            | ("sum" | "prod" | "integ")
+//!              "(" name "," expression "," expression "," expression ")"
+//!            | "deriv" "(" name "," expression "," expression ")"
+//!            | ("root" | "minimize") "(" expression "," name "," expression "," expression ")"
+//! argument   = "(" expression ")" | operand, extended over any `*`, `/`, and powers after it
+//! number     = digit* ("." digit*)?
+//! name       = letter (letter | digit)*
+//! ```
+//!
+//! Square brackets and braces group just like parentheses wherever `"("` and `")"` appear, each
+//! closed by its own kind, so `{[2+3]*4}` is 20 while `[2+3)` fails with
+//! [`Error::MismatchedBracket`].
+//!
+//! `function` is a name that is a built-in function like `sin` or one of
+//! [`Environment::functions`], unless a variable has the name. Any other name reads as a variable,
+//! which is only looked up when evaluating, so an unknown one is not an error to the parser.
+//!
+//! The operators bind from loosest to tightest as follows, each tighter than `-` in front of an
+//! operand except powers:
+//!
+//! 1. `^` in [`Environment::programmer_mode`], where it is exclusive or
+//! 2. `+` and `-`
+//! 3. `*`, `/`, `.`, `·`, and `×`
+//! 4. `^`, `**`, and `↑`, which group right to left
+//!
+//! Juxtaposition, as in `2 x` or `2(x)`, an `argument` after an operand, multiplies just that
+//! operand, however tightly the operator before it binds. An argument that is not in brackets runs
+//! on over any `*`, `/`, and powers after it, up to a `+` or `-`.
+//!
+//! So `-2^2` is -4, `2 x^2` is `2 (x^2)`, `1/2 x` is `1/(2 x)`, and `2 x * y` is `2 (x * y)`, while
+//! `2(x) * y` is `(2 x) * y`. A number runs on through any letters after it, so `2x` is an invalid
+//! number rather than `2*x`. It is decimal even with leading zeros, and the prefixes `0b`, `0o`,
+//! and `0x` are reserved. `∑`, `∏`, and `√` stand for `sum`, `prod`, and `sqrt`. The `±` of
+//! [`eval_plus_minus`], the `table` of [`table`], and the `base` of [`base`] are read before
+//! parsing, and are not part of the grammar.
 
-#[derive(Debug)]
+extern crate alloc;
+
+use alloc::rc::Rc;
+use core::{
+    cell::{Cell, RefCell},
+    fmt,
+    iter::{self, Peekable},
+    ops::RangeInclusive,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Why an expression could not be evaluated.
+///
+/// Positions are byte offsets into the evaluated text.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
-    Unrecognized,
-    Invalid,
+    /// There was nothing to evaluate
+    Empty,
+    /// A character that cannot start any token
+    UnrecognizedChar { found: char, pos: usize },
+    /// A name that is not a variable, constant, or function
+    UnknownIdentifier(String),
+    /// A token that cannot appear where it does
+    UnexpectedToken { found: String, pos: usize },
+    /// The expression ended where more was expected, such as after an operator
+    UnexpectedEnd,
+    /// A parenthesis without a partner
+    UnbalancedParen(usize),
+    /// A closing bracket of a different kind than the one it closes, as in `[2+3)`
+    MismatchedBracket { found: char, pos: usize },
+    /// A numeric token that is not a valid number
+    InvalidNumber { found: String, pos: usize },
+    /// A number written with one of the [`RADIX_PREFIXES`], like `0o17`, which are reserved for
+    /// other bases rather than read as decimal
+    RadixPrefix { found: String, pos: usize },
+    /// A function given the wrong number of arguments, at the `(` of its argument list
+    ArgumentCount {
+        expected: usize,
+        found: usize,
+        pos: usize,
+    },
+    /// An operand outside the domain of its operation, such as a fraction given to XOR
+    DomainError,
+    /// A sum or product with more terms than [`Environment::summation_limit`] allows
+    TooManyTerms,
+    /// Text longer than [`MAX_EXPRESSION_LEN`], which is not read at all, or with more than
+    /// [`MAX_LEXEMES`] tokens and groups
+    TooLong,
+    /// A division by zero, or zero raised to a negative power, which instead give infinity or NaN
+    /// with [`Environment::ieee_division`]
+    DivByZero,
+    /// A cell whose formula refers back to itself, through other cells or directly
+    CircularReference(String),
+    /// Evaluation ran past [`Environment::deadline`]
+    Timeout,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::Unrecognized => "<unrecognized operator>",
-            Self::Invalid => "<invalid expression>",
+            Self::UnrecognizedChar { .. } | Self::UnknownIdentifier(_) => "<unrecognized operator>",
+            Self::Empty
+            | Self::UnexpectedToken { .. }
+            | Self::UnexpectedEnd
+            | Self::UnbalancedParen(_)
+            | Self::MismatchedBracket { .. }
+            | Self::InvalidNumber { .. }
+            | Self::ArgumentCount { .. }
+            | Self::DomainError
+            | Self::TooManyTerms => "<invalid expression>",
+            Self::RadixPrefix { .. } => "<unsupported number base>",
+            Self::DivByZero => "<division by zero>",
+            Self::CircularReference(_) => "<circular reference>",
+            Self::Timeout => "<took too long>",
+            Self::TooLong => "<expression too long>",
+        }
+        .fmt(f)
+    }
+}
+
+impl Error {
+    /// Whether the text could not be read as an expression at all, as opposed to being well-formed
+    /// math that failed to evaluate, like a division by zero
+    #[must_use]
+    pub const fn is_unreadable(&self) -> bool {
+        matches!(
+            self,
+            Self::Empty
+                | Self::UnrecognizedChar { .. }
+                | Self::UnknownIdentifier(_)
+                | Self::UnexpectedToken { .. }
+                | Self::UnexpectedEnd
+                | Self::UnbalancedParen(_)
+                | Self::MismatchedBracket { .. }
+                | Self::InvalidNumber { .. }
+                | Self::RadixPrefix { .. }
+                | Self::TooLong
+        )
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Why text could not be parsed, as given by [`parse_str`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset into the text of the problem, which is the end of the text, without trailing
+    /// whitespace, for an expression that ends too soon
+    pub pos: usize,
+    /// What the problem is, in a form to show to people
+    pub message: String,
+    pub error: Error,
+}
+
+impl ParseError {
+    /// Locate and describe `error`, which parsing `text` failed with
+    fn new(error: Error, text: &str) -> Self {
+        let (pos, message) = match &error {
+            Error::Empty => (0, "there is nothing to read".to_owned()),
+            Error::UnrecognizedChar { found, pos } => {
+                (*pos, format!("`{found}` cannot be part of an expression"))
+            }
+            Error::UnexpectedToken { found, pos } => {
+                (*pos, format!("`{found}` is unexpected here"))
+            }
+            Error::UnexpectedEnd => (
+                text.trim_end().len(),
+                "the expression ends where more was expected".to_owned(),
+            ),
+            Error::UnbalancedParen(pos) => (*pos, "this parenthesis has no partner".to_owned()),
+            Error::MismatchedBracket { found, pos } => (
+                *pos,
+                format!("`{found}` closes a different kind of bracket"),
+            ),
+            Error::InvalidNumber { found, pos } => (*pos, format!("`{found}` is not a number")),
+            Error::RadixPrefix { found, pos } => (
+                *pos,
+                format!("`{found}` is not decimal, and other bases are not supported"),
+            ),
+            Error::ArgumentCount {
+                expected,
+                found,
+                pos,
+            } => (
+                *pos,
+                format!("expected {expected} arguments, but found {found}"),
+            ),
+            Error::TooLong => (
+                0,
+                format!(
+                    "the expression is longer than {MAX_EXPRESSION_LEN} bytes or \
+                     {MAX_LEXEMES} tokens"
+                ),
+            ),
+            // These only come from evaluating
+            error => (0, error.to_string()),
+        };
+        Self {
+            pos,
+            message,
+            error,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.pos)
+    }
+}
+
+/// A sign that a successful result may not mean what it seems to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Warning {
+    /// The result is too large to represent, so it became infinite
+    Overflow,
+    /// Nearly equal values were subtracted, so rounding errors may dominate the result
+    PrecisionLoss,
+    /// The result is NaN
+    NotFinite,
+    /// Text after the expression was left out, under [`Environment::lenient_trailing`]
+    IgnoredTrailing,
+    /// A variable or constant right before `(` was multiplied by what follows, as in `sln(2)` with
+    /// a variable `sln`, where a misspelled function was more likely meant. Only noted under
+    /// [`Environment::strict_identifiers`]
+    MultipliedName,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Overflow => "result overflowed to infinity",
+            Self::PrecisionLoss => "possible precision loss",
+            Self::NotFinite => "result is not a finite number",
+            Self::IgnoredTrailing => "trailing text was ignored",
+            Self::MultipliedName => "a name before `(` was multiplied, not called",
         }
         .fmt(f)
     }
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
+/// The result of evaluating an expression
+pub type Value = f128;
+
+/// A user-supplied single-argument function
+pub type Function = Rc<dyn Fn(Value) -> Result<Value>>;
+
+/// The unit trigonometric functions take and inverse trigonometric functions return
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AngleMode {
+    #[default]
+    Radians,
+    Degrees,
+}
+
+impl AngleMode {
+    const fn to_radians(self, x: Value) -> Value {
+        match self {
+            Self::Radians => x,
+            Self::Degrees => x.to_radians(),
+        }
+    }
+
+    const fn radians_to_unit(self, x: Value) -> Value {
+        match self {
+            Self::Radians => x,
+            Self::Degrees => x.to_degrees(),
+        }
+    }
+}
+
+/// The base `log` takes logarithms in
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LogBase {
+    #[default]
+    Ten,
+    /// Base e, as `log` often means in mathematics and the sciences
+    Natural,
+}
+
+/// Default for [`Environment::summation_limit`]
+pub const DEFAULT_SUMMATION_LIMIT: u32 = 100_000;
+/// Values [`Environment::summation_limit`] is kept within
+pub const SUMMATION_LIMITS: RangeInclusive<u32> = 1..=10_000_000;
+/// Default for [`Environment::integration_steps`]
+pub const DEFAULT_INTEGRATION_STEPS: u32 = 1000;
+/// Values [`Environment::integration_steps`] is kept within
+pub const INTEGRATION_STEPS: RangeInclusive<u32> = 2..=1_000_000;
+/// Default for [`Environment::scientific_above`]
+pub const DEFAULT_SCIENTIFIC_ABOVE: i32 = 15;
+/// Default for [`Environment::scientific_below`]
+pub const DEFAULT_SCIENTIFIC_BELOW: i32 = -6;
+/// Most bytes of text read as an expression.
+///
+/// Longer text, usually pasted by accident, fails with [`Error::TooLong`] before it is lexed, so
+/// that it cannot stall the editor.
+pub const MAX_EXPRESSION_LEN: usize = 10_000;
+/// Most tokens and groups in an expression. Parsing and evaluating recurse at most once for each,
+/// so this keeps deeply nested text from overflowing the stack.
+const MAX_LEXEMES: usize = 1000;
+
+/// Everything besides the text itself that affects how an expression is evaluated
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Environment {
+    /// Variables, which take precedence over built-in constants of the same name
+    pub variables: HashMap<String, Value>,
+    /// Functions, which take precedence over built-in functions of the same name
+    pub functions: HashMap<String, Function>,
+    /// Values of cells like `A1`, as found by [`evaluate_cells`], which variables take precedence
+    /// over. A cell that failed fails any expression naming it with the same error.
+    pub cells: HashMap<String, Result<Value>>,
+    pub angle_mode: AngleMode,
+    /// What `log` means, which is base 10 by default.
+    ///
+    /// This silently changes the result of every `log`, so `log10` and `ln`, which never change,
+    /// are safer in notes that may be read with either setting.
+    pub log_base: LogBase,
+    /// Number of decimal places results are displayed with, or `None` for as many as needed
+    pub precision: Option<usize>,
+    /// Whether results are displayed by [`format_value`] with the [`EXTENDED_DIGITS`] significant
+    /// digits a [`Value`] holds, rather than rounded to the 17 or so of an `f64`
+    pub extended_digits: bool,
+    /// Most terms `sum` and `prod` will evaluate before giving up with [`Error::TooManyTerms`].
+    ///
+    /// Each term evaluates the body once, so this bounds how long a runaway sum can take.
+    pub summation_limit: u32,
+    /// Number of subdivisions `integ` uses.
+    ///
+    /// The integrand is evaluated once per subdivision, so doubling this doubles the cost of an
+    /// integral; Simpson's rule converges quickly, so the default is plenty for smooth functions.
+    pub integration_steps: u32,
+    /// Whether `^` means bitwise exclusive or, as in most programming languages, rather than
+    /// exponentiation.
+    ///
+    /// Exclusive or only accepts whole numbers, and binds more loosely than `+` and `-`, so
+    /// `1 + 2 ^ 3` is `(1 + 2) ^ 3`. `**` is exponentiation either way.
+    pub programmer_mode: bool,
+    /// Whether dividing by zero, including raising zero to a negative power, follows IEEE 754,
+    /// giving infinity or NaN, rather than failing with [`Error::DivByZero`]
+    pub ieee_division: bool,
+    /// Whether text after an expression that keeps it from being read, like the unit in
+    /// `2 + 3 seconds`, is left out with [`Warning::IgnoredTrailing`] rather than failing
+    pub lenient_trailing: bool,
+    /// Results at least 10 to this power in magnitude are displayed in scientific notation
+    pub scientific_above: i32,
+    /// Nonzero results less than 10 to this power in magnitude are displayed in scientific notation
+    pub scientific_below: i32,
+    /// Time after which evaluation gives up with [`Error::Timeout`], or `None` for no limit.
+    ///
+    /// It is checked every so often while `sum`, `prod`, and `integ` evaluate their bodies, which
+    /// is where nearly all of the time goes, so evaluation can overrun it slightly.
+    pub deadline: Option<Instant>,
+    /// Bodies of bound forms evaluated so far, counted across the whole evaluation so that nested
+    /// forms check [`Self::deadline`] as often as a single one does
+    pub(crate) iterations: Cell<u32>,
+    /// Whether a variable or constant written right before `(`, with no space between, is noted
+    /// with [`Warning::MultipliedName`].
+    ///
+    /// Names are never split into letters, so a misspelled function like `sln(2)` fails as an
+    /// unknown name, unless it happens to be a variable, in which case it is silently multiplied.
+    pub strict_identifiers: bool,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self {
+            variables: HashMap::new(),
+            functions: HashMap::new(),
+            cells: HashMap::new(),
+            angle_mode: AngleMode::default(),
+            log_base: LogBase::default(),
+            precision: None,
+            extended_digits: false,
+            summation_limit: DEFAULT_SUMMATION_LIMIT,
+            integration_steps: DEFAULT_INTEGRATION_STEPS,
+            programmer_mode: false,
+            ieee_division: false,
+            lenient_trailing: false,
+            scientific_above: DEFAULT_SCIENTIFIC_ABOVE,
+            scientific_below: DEFAULT_SCIENTIFIC_BELOW,
+            deadline: None,
+            iterations: Cell::new(0),
+            strict_identifiers: false,
+        }
+    }
+}
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 enum TokenType {
     Num,
     Id,
     Sym,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Token {
     text: String,
     ty: TokenType,
+    pos: usize,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Group {
     inner: Vec<Lexeme>,
+    /// The opening bracket, `(`, `[`, or `{`
+    open: char,
+    /// Position of the opening bracket
+    pos: usize,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum Lexeme {
     Token(Token),
     Group(Group),
 }
 
-fn lex<I: Iterator<Item = char>>(text: &mut Peekable<I>, term: char) -> Result<Vec<Lexeme>> {
+impl Lexeme {
+    /// The error for this lexeme appearing somewhere it cannot
+    fn unexpected(&self) -> Error {
+        match self {
+            Self::Token(Token { text, pos, .. }) => Error::UnexpectedToken {
+                found: text.clone(),
+                pos: *pos,
+            },
+            Self::Group(Group { open, pos, .. }) => Error::UnexpectedToken {
+                found: open.to_string(),
+                pos: *pos,
+            },
+        }
+    }
+}
+
+/// The error for `lexeme` appearing somewhere it cannot, where `None` is the end of the input
+fn unexpected(lexeme: Option<&Lexeme>) -> Error {
+    lexeme.map_or(Error::UnexpectedEnd, Lexeme::unexpected)
+}
+
+/// The token starting with `first` and continuing with as many of the next characters in `text` as
+/// satisfy `continues`
+fn lex_while(
+    text: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+    first: char,
+    continues: impl Fn(char) -> bool,
+) -> String {
+    let mut token = String::from(first);
+    while let Some((_, x)) = text.next_if(|&(_, x)| continues(x)) {
+        token.push(x);
+    }
+    token
+}
+
+/// Lex `text`, a stream of characters and their positions, up to the bracket closing the one at
+/// `open`, or to the end if `open` is `None`.
+///
+/// Square brackets and braces group just like parentheses, but each must be closed by its own kind.
+fn lex(
+    text: &mut Peekable<impl Iterator<Item = (usize, char)>>,
+    open: Option<(usize, char)>,
+) -> Result<Vec<Lexeme>> {
     let mut result = Vec::new();
-    while let Some(&x) = text.peek() {
+    while let Some(&(pos, x)) = text.peek() {
         match x {
+            // Any alphabetic character starts an identifier, so Greek letters like `θ` can name
+            // variables
             x if x.is_alphabetic() => {
-                let mut token = String::from(x);
                 text.next();
-                while let Some(x) = text.peek() {
-                    if x.is_alphanumeric() {
-                        token.push(*x);
-                        text.next();
-                    } else {
-                        break;
-                    }
-                }
                 result.push(Lexeme::Token(Token {
-                    text: token,
+                    text: lex_while(text, x, char::is_alphanumeric),
                     ty: TokenType::Id,
+                    pos,
                 }));
             }
             x if x.is_numeric() || x == '.' => {
-                let mut token = String::from(x);
                 text.next();
-                while let Some(x) = text.peek() {
-                    if x.is_alphanumeric() || *x == '.' {
-                        token.push(*x);
-                        text.next();
-                    } else {
-                        break;
+                // A `.` that does not start a number, like the one in `3.5 . 2`, is a
+                // multiplication dot
+                let token = if x == '.' && !text.peek().is_some_and(|(_, x)| x.is_numeric()) {
+                    Token {
+                        text: x.into(),
+                        ty: TokenType::Sym,
+                        pos,
                     }
-                }
+                } else {
+                    Token {
+                        text: lex_while(text, x, |x| x.is_alphanumeric() || x == '.'),
+                        ty: TokenType::Num,
+                        pos,
+                    }
+                };
+                result.push(Lexeme::Token(token));
+            }
+            // The symbols are aliases for the functions, forms, and operators, taking the same
+            // arguments, except `±`, which is left for `eval_plus_minus` to replace, and `·` and
+            // `↑`, which are operators of their own
+            '∑' | '∏' | '√' | '×' | '±' | '·' | '↑' => {
+                text.next();
+                let (name, ty) = match x {
+                    '∑' => ("sum", TokenType::Id),
+                    '∏' => ("prod", TokenType::Id),
+                    '√' => ("sqrt", TokenType::Id),
+                    '±' => ("±", TokenType::Sym),
+                    '·' => ("·", TokenType::Sym),
+                    '↑' => ("↑", TokenType::Sym),
+                    _ => ("*", TokenType::Sym),
+                };
                 result.push(Lexeme::Token(Token {
-                    text: token,
-                    ty: TokenType::Num,
+                    text: name.into(),
+                    ty,
+                    pos,
                 }));
             }
-            '+' | '-' | '/' | '^' => {
+            '+' | '-' | '/' | '^' | ',' | '=' => {
                 text.next();
+                let mut op = String::from(x);
+                if matches!(x, '+' | '-' | '/') && text.peek().map(|&(_, x)| x) == Some('=') {
+                    text.next();
+                    op.push('=');
+                }
                 result.push(Lexeme::Token(Token {
-                    text: x.into(),
+                    text: op,
                     ty: TokenType::Sym,
+                    pos,
                 }));
             }
+            // Longest match, so `3***4` is `3 ** *4`, which the parser rejects because `*` cannot
+            // start an operand, and a trailing `*` or `**` fails with `Error::UnexpectedEnd`
             '*' => {
                 text.next();
-                if text.peek() == Some(&'*') {
+                let op = match text.peek().map(|&(_, x)| x) {
+                    Some('*') => "**",
+                    Some('=') => "*=",
+                    _ => "*",
+                };
+                if op.len() == 2 {
                     text.next();
-                    result.push(Lexeme::Token(Token {
-                        text: "**".into(),
-                        ty: TokenType::Sym,
-                    }));
-                } else {
-                    result.push(Lexeme::Token(Token {
-                        text: "*".into(),
-                        ty: TokenType::Sym,
-                    }));
                 }
+                result.push(Lexeme::Token(Token {
+                    text: op.into(),
+                    ty: TokenType::Sym,
+                    pos,
+                }));
             }
-            '(' => {
+            '(' | '[' | '{' => {
                 text.next();
-                let inner = lex(text, ')')?;
-                result.push(Lexeme::Group(Group { inner }));
+                let inner = lex(text, Some((pos, x)))?;
+                result.push(Lexeme::Group(Group {
+                    inner,
+                    open: x,
+                    pos,
+                }));
             }
-            x if x == term => {
+            ')' | ']' | '}' => {
+                let Some((_, opener)) = open else {
+                    Err(Error::UnbalancedParen(pos))?
+                };
+                if closing_bracket(opener) != x {
+                    Err(Error::MismatchedBracket { found: x, pos })?;
+                }
                 text.next();
-                break;
+                return Ok(result);
             }
             x if x.is_whitespace() => {
                 text.next();
             }
-            _ => Err(Error::Unrecognized)?,
+            found => Err(Error::UnrecognizedChar { found, pos })?,
         }
     }
-    Ok(result)
+    open.map_or(Ok(result), |(pos, _)| Err(Error::UnbalancedParen(pos)))
 }
 
-enum BinOp {
+/// The bracket that closes `open`
+const fn closing_bracket(open: char) -> char {
+    match open {
+        '[' => ']',
+        '{' => '}',
+        _ => ')',
+    }
+}
+
+/// An operation of an [`Expression`] on two operands
+#[derive(Clone, Copy)]
+pub enum BinOp {
     Add,
     Sub,
     Mul,
+    /// Multiplication written by juxtaposition, like `2x`
+    ImplicitMul,
     Div,
     Pow,
+    Xor,
+}
+
+/// A binary operator as written, with its binding powers.
+///
+/// An operator binds its operands more tightly the higher its powers are, and a left power above
+/// the right makes it right-associative.
+struct Operator {
+    symbol: &'static str,
+    bp: (u8, u8),
+    op: BinOp,
+}
+
+/// The binary operators written with a symbol, the one place their precedence is defined.
+///
+/// Juxtaposition has no symbol, and no left power either; see [`JUXTAPOSITION_BP`].
+const OPERATORS: [Operator; 9] = [
+    Operator {
+        symbol: "+",
+        bp: (3, 4),
+        op: BinOp::Add,
+    },
+    Operator {
+        symbol: "-",
+        bp: (3, 4),
+        op: BinOp::Sub,
+    },
+    Operator {
+        symbol: "*",
+        bp: (7, 8),
+        op: BinOp::Mul,
+    },
+    Operator {
+        symbol: ".",
+        bp: (7, 8),
+        op: BinOp::Mul,
+    },
+    Operator {
+        symbol: "·",
+        bp: (7, 8),
+        op: BinOp::Mul,
+    },
+    Operator {
+        symbol: "/",
+        bp: (7, 8),
+        op: BinOp::Div,
+    },
+    Operator {
+        symbol: "^",
+        bp: (10, 9),
+        op: BinOp::Pow,
+    },
+    Operator {
+        symbol: "**",
+        bp: (10, 9),
+        op: BinOp::Pow,
+    },
+    // Knuth's up-arrow, which stays a power in programmer mode like `**`
+    Operator {
+        symbol: "↑",
+        bp: (10, 9),
+        op: BinOp::Pow,
+    },
+];
+
+/// Right binding power of juxtaposition, which multiplies an operand by an `argument` after it.
+///
+/// Juxtaposition is checked against no left power, so it takes the operand just before it however
+/// tightly the operator before that binds, making `1/2 x` be `1/(2 x)` and `2^3 x` be `2^(3 x)`. An
+/// argument that is not in brackets then runs on over the operators binding at least this tightly,
+/// `*`, `/`, and powers, so `2 x * y` is `2 (x * y)`, while `+` and `-` end it.
+const JUXTAPOSITION_BP: u8 = 6;
+
+/// Operators that replace those in [`OPERATORS`] in programmer mode
+const PROGRAMMER_OPERATORS: [Operator; 1] = [Operator {
+    symbol: "^",
+    bp: (1, 2),
+    op: BinOp::Xor,
+}];
+
+impl Operator {
+    /// The binary operator written `symbol`, if there is one
+    fn find(symbol: &str, env: &Environment) -> Option<&'static Self> {
+        let programmer: &[_] = if env.programmer_mode {
+            &PROGRAMMER_OPERATORS
+        } else {
+            &[]
+        };
+        programmer
+            .iter()
+            .chain(&OPERATORS)
+            .find(|x| x.symbol == symbol)
+    }
+}
+
+impl BinOp {
+    /// Apply the operator to its evaluated operands
+    #[allow(clippy::float_cmp)]
+    fn apply(
+        self,
+        lhs: f128,
+        rhs: f128,
+        env: &Environment,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<f128> {
+        Ok(match self {
+            Self::Add => add(lhs, rhs, warnings),
+            Self::Sub => add(lhs, -rhs, warnings),
+            Self::Mul | Self::ImplicitMul => lhs * rhs,
+            Self::Div => {
+                if rhs == 0.0 && !env.ieee_division {
+                    Err(Error::DivByZero)?;
+                }
+                lhs / rhs
+            }
+            Self::Pow => pow(lhs, rhs, env.ieee_division)?,
+            Self::Xor => xor(lhs, rhs)?,
+        })
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul | Self::ImplicitMul => "*",
+            Self::Div => "/",
+            Self::Pow => "^",
+            Self::Xor => "xor",
+        }
+        .fmt(f)
+    }
 }
 
-enum UnOp {
-    Fn(Box<dyn Fn(f128) -> Result<f128>>),
+type UnaryFn = Box<dyn Fn(f128) -> Result<f128>>;
+
+/// An operation of an [`Expression`] on one operand, which may be a function like `sin`
+pub enum UnOp {
+    Fn { name: String, func: UnaryFn },
     Pos,
     Neg,
 }
 
 impl UnOp {
-    fn func(func: impl Fn(f128) -> Result<f128> + 'static) -> Self {
-        Self::Fn(Box::new(func))
+    fn func(name: &str, func: impl Fn(f128) -> Result<f128> + 'static) -> Self {
+        Self::Fn {
+            name: name.to_owned(),
+            func: Box::new(func),
+        }
+    }
+}
+
+/// A form that evaluates its body repeatedly with a variable bound to different values
+#[derive(Clone, Copy)]
+pub enum BoundForm {
+    /// `sum(var, from, to, body)`: the sum of `body` for each whole number from `from` to `to`
+    Sum { limit: u32 },
+    /// `prod(var, from, to, body)`: the product of `body` for each whole number from `from` to `to`
+    Prod { limit: u32 },
+    /// `integ(var, from, to, body)`: the integral of `body` by Simpson's rule
+    Integ { steps: u32 },
+    /// `deriv(var, at, body)`: the derivative of `body` at `at` by central difference
+    Deriv,
+    /// `root(body, var, from, to)`: where `body` is zero between `from` and `to`, by bisection
+    Root,
+    /// `minimize(body, var, from, to)`: where `body` is smallest between `from` and `to`
+    Minimize,
+}
+
+impl BoundForm {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Sum { .. } => "sum",
+            Self::Prod { .. } => "prod",
+            Self::Integ { .. } => "integ",
+            Self::Deriv => "deriv",
+            Self::Root => "root",
+            Self::Minimize => "minimize",
+        }
+    }
+
+    /// Whether the body comes first in the argument list, as it does for the forms that solve for
+    /// their variable, rather than last
+    const fn body_first(self) -> bool {
+        self.solves()
+    }
+
+    /// Whether the form solves for its variable, evaluating its body at points that only narrow
+    /// down the result, so the warnings they give, like a loss of precision near a root, are not
+    /// the result's
+    const fn solves(self) -> bool {
+        matches!(self, Self::Root | Self::Minimize)
+    }
+
+    /// Evaluate the form, where `at` evaluates the body with the variable bound to its argument
+    fn eval(self, args: &[f128], mut at: impl FnMut(f128) -> Result<f128>) -> Result<f128> {
+        Ok(match self {
+            Self::Sum { limit } | Self::Prod { limit } => {
+                let (from, to) = (args[0].ceil(), args[1].floor());
+                if to - from >= f128::from(limit) {
+                    Err(Error::TooManyTerms)?;
+                }
+                let mut result = if matches!(self, Self::Sum { .. }) {
+                    0.0
+                } else {
+                    1.0
+                };
+                for i in (0..limit)
+                    .map(|i| from + f128::from(i))
+                    .take_while(|&i| i <= to)
+                {
+                    if matches!(self, Self::Sum { .. }) {
+                        result += at(i)?;
+                    } else {
+                        result *= at(i)?;
+                    }
+                }
+                result
+            }
+            Self::Integ { steps } => {
+                let steps = steps + steps % 2;
+                let (from, to) = (args[0], args[1]);
+                let width = (to - from) / f128::from(steps);
+                let mut result = at(from)? + at(to)?;
+                for i in 1..steps {
+                    let weight = if i % 2 == 1 { 4.0 } else { 2.0 };
+                    result += weight * at(width.mul_add(f128::from(i), from))?;
+                }
+                result * width / 3.0
+            }
+            Self::Deriv => {
+                let x = args[0];
+                let h = 1e-12 * x.abs().max(1.0);
+                (at(x + h)? - at(x - h)?) / (2.0 * h)
+            }
+            Self::Root => bisect(args[0], args[1], at)?,
+            Self::Minimize => minimum(args[0], args[1], at)?,
+        })
+    }
+}
+
+/// Most halvings `root` makes, which is more than enough to narrow any interval to adjacent values
+const BISECTIONS: u32 = 16_500;
+
+/// Where `f` is zero between `from` and `to`, found by bisection.
+///
+/// `f` must have opposite signs at the two ends, or else there may be no zero and this fails with
+/// [`Error::DomainError`]. The interval is halved until its ends are adjacent values, so the zero
+/// is as exact as `f` allows; with several zeros, any one of them may be found.
+#[allow(clippy::float_cmp)]
+fn bisect(from: f128, to: f128, mut f: impl FnMut(f128) -> Result<f128>) -> Result<f128> {
+    let (mut lo, mut hi) = (from, to);
+    let (f_lo, f_hi) = (f(lo)?, f(hi)?);
+    if f_lo == 0.0 {
+        return Ok(lo);
+    }
+    if f_hi == 0.0 {
+        return Ok(hi);
+    }
+    if !(f_lo.is_sign_negative() ^ f_hi.is_sign_negative()) || f_lo.is_nan() || f_hi.is_nan() {
+        Err(Error::DomainError)?;
+    }
+    for _ in 0..BISECTIONS {
+        let mid = lo + (hi - lo) / 2.0;
+        if mid == lo || mid == hi {
+            break;
+        }
+        let f_mid = f(mid)?;
+        if f_mid == 0.0 {
+            return Ok(mid);
+        }
+        if f_mid.is_sign_negative() == f_lo.is_sign_negative() {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Ok(lo + (hi - lo) / 2.0)
+}
+
+/// Number of evenly spaced points `minimize` samples before refining the smallest
+const MINIMIZE_SAMPLES: u16 = 100;
+
+/// Steps of golden-section search `minimize` refines with, each narrowing the interval to 0.618
+/// times its width, so that it shrinks by a factor of about 1e33
+const GOLDEN_STEPS: u32 = 160;
+
+/// Where `f` is smallest between `from` and `to`.
+///
+/// `f` is sampled at [`MINIMIZE_SAMPLES`] evenly spaced points, and the smallest sample is refined
+/// by golden-section search between its neighbours. A minimum narrower than the spacing of the
+/// samples can be missed. Near a minimum, `f` changes with the square of the distance from it, so
+/// rounding in `f` limits the result to about half as many correct digits as `f` has.
+fn minimum(from: f128, to: f128, mut at: impl FnMut(f128) -> Result<f128>) -> Result<f128> {
+    if from >= to || from.is_nan() || to.is_nan() {
+        Err(Error::DomainError)?;
+    }
+    let spacing = (to - from) / f128::from(MINIMIZE_SAMPLES);
+    let mut best = (from, at(from)?);
+    for i in 1..=MINIMIZE_SAMPLES {
+        let x = spacing.mul_add(f128::from(i), from);
+        let y = at(x)?;
+        if y < best.1 {
+            best = (x, y);
+        }
+    }
+    let (mut lo, mut hi) = ((best.0 - spacing).max(from), (best.0 + spacing).min(to));
+    let ratio = (5.0_f128.sqrt() - 1.0) / 2.0;
+    let (mut left, mut right) = ((-ratio).mul_add(hi - lo, hi), ratio.mul_add(hi - lo, lo));
+    let (mut at_left, mut at_right) = (at(left)?, at(right)?);
+    for _ in 0..GOLDEN_STEPS {
+        if at_left < at_right {
+            (hi, right, at_right) = (right, left, at_left);
+            left = (-ratio).mul_add(hi - lo, hi);
+            at_left = at(left)?;
+        } else {
+            (lo, left, at_left) = (left, right, at_right);
+            right = ratio.mul_add(hi - lo, lo);
+            at_right = at(right)?;
+        }
     }
+    Ok(lo + (hi - lo) / 2.0)
 }
 
-// impl fmt::Debug for UnOp {
-//     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-//         match self {
-//             Self::Fn(_) => write!(f, "fn()"),
-//             Self::Pos => write!(f, "+"),
-//             Self::Neg => write!(f, "-"),
-//         }
-//     }
-// }
+type CallFn = Box<dyn Fn(&[f128]) -> Result<f128>>;
 
-enum Expression {
+/// An expression as read by [`parse_str`], which displays with every operation parenthesized
+pub enum Expression {
     BinOp {
-        lhs: Box<Expression>,
+        lhs: Box<Self>,
         op: BinOp,
-        rhs: Box<Expression>,
+        rhs: Box<Self>,
     },
     UnOp {
         op: UnOp,
-        inner: Box<Expression>,
+        inner: Box<Self>,
+    },
+    Call {
+        name: String,
+        func: CallFn,
+        args: Vec<Self>,
     },
+    Bound {
+        form: BoundForm,
+        var: String,
+        args: Vec<Self>,
+        body: Box<Self>,
+    },
+    /// A variable, bound either by an enclosing [`Self::Bound`] or by the environment
+    Var(String),
     Num(f128),
 }
 
 impl Expression {
-    fn func(func: impl Fn(f128) -> Result<f128> + 'static, arg: Self) -> Self {
+    fn func(name: &str, func: impl Fn(f128) -> Result<f128> + 'static, arg: Self) -> Self {
         Self::UnOp {
-            op: UnOp::func(func),
+            op: UnOp::func(name, func),
             inner: Box::new(arg),
         }
     }
-}
 
-impl Expression {
-    fn eval(&self) -> Result<f128> {
-        Ok(match self {
-            Self::BinOp { lhs, op, rhs } => match op {
-                BinOp::Add => lhs.eval()? + rhs.eval()?,
-                BinOp::Sub => lhs.eval()? - rhs.eval()?,
-                BinOp::Mul => lhs.eval()? * rhs.eval()?,
-                BinOp::Div => lhs.eval()? / rhs.eval()?,
-                BinOp::Pow => lhs.eval()?.powf(rhs.eval()?),
-            },
-            Self::UnOp { op, inner } => match op {
-                UnOp::Pos => inner.eval()?,
-                UnOp::Neg => -inner.eval()?,
-                UnOp::Fn(x) => x(inner.eval()?)?,
-            },
-            Self::Num(x) => *x,
-        })
+    fn call(name: &str, func: impl Fn(&[f128]) -> Result<f128> + 'static, args: Vec<Self>) -> Self {
+        Self::Call {
+            name: name.to_owned(),
+            func: Box::new(func),
+            args,
+        }
     }
-}
 
-fn bin_bp(op: &str) -> (u8, u8) {
-    match op {
-        "+" | "-" => (1, 2),
-        " " => (3, 4),
-        "*" | "/" => (5, 6),
-        "^" | "**" => (8, 7),
-        _ => unreachable!(),
+    const fn is_implicit_mul(&self) -> bool {
+        matches!(
+            self,
+            Self::BinOp {
+                op: BinOp::ImplicitMul,
+                ..
+            }
+        )
     }
-}
 
-fn parse_num(text: &str) -> Result<f128> {
-    let mut int_part = 0.0;
-    let mut chars = text.chars();
-    for c in &mut chars {
-        match c {
-            '0'..='9' => {
-                int_part *= 10.0;
-                int_part += f128::from(f64::from(c as u32 - '0' as u32));
+    /// Whether an implicit product is an operand of a division or power, like `1/2 x` or `2^3 x`,
+    /// where readers often expect different grouping than the parser's
+    fn is_ambiguous(&self) -> bool {
+        match self {
+            Self::BinOp { lhs, op, rhs } => {
+                (matches!(op, BinOp::Div | BinOp::Pow)
+                    && (lhs.is_implicit_mul() || rhs.is_implicit_mul()))
+                    || lhs.is_ambiguous()
+                    || rhs.is_ambiguous()
             }
-            '.' => break,
-            _ => Err(Error::Invalid)?,
+            Self::UnOp { inner, .. } => inner.is_ambiguous(),
+            Self::Call { args, .. } => args.iter().any(Self::is_ambiguous),
+            Self::Bound { args, body, .. } => {
+                args.iter().any(Self::is_ambiguous) || body.is_ambiguous()
+            }
+            Self::Var(_) | Self::Num(_) => false,
         }
     }
-    let mut float_part = 0.0;
-    let mut multiplier = 0.1;
-    for c in &mut chars {
-        match c {
-            '0'..='9' => {
-                float_part += f128::from(f64::from(c as u32 - '0' as u32)) * multiplier;
-                multiplier /= 10.0;
-            }
-            '.' => break,
-            _ => Err(Error::Invalid)?,
+
+    /// Whether this is built from numbers, variables, `+`, `-`, and `*` alone, so that it cannot
+    /// fail once its variables are defined
+    fn is_plain(&self) -> bool {
+        match self {
+            Self::BinOp {
+                lhs,
+                op: BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::ImplicitMul,
+                rhs,
+            } => lhs.is_plain() && rhs.is_plain(),
+            Self::UnOp {
+                op: UnOp::Pos | UnOp::Neg,
+                inner,
+            } => inner.is_plain(),
+            Self::Var(_) | Self::Num(_) => true,
+            _ => false,
         }
     }
-    Ok(int_part + float_part)
-}
 
-fn parse_arg(iter: &mut Peekable<impl Iterator<Item = &Lexeme>>) -> Result<Expression> {
-    match iter.peek() {
-        Some(Lexeme::Group(_)) => parse_atom(iter),
-        _ => parse_bp(iter, 4),
+    /// This expression with the identities `x + 0 = x`, `x - 0 = x`, `x * 1 = x`, `x * 0 = 0`,
+    /// `x^1 = x`, and `x^0 = 1` applied throughout, along with `0 + x = x`, `1 * x = x`, and
+    /// `0 * x = 0`.
+    ///
+    /// These are the only rewrites, and they keep the value the same for any finite numbers the
+    /// variables stand for. An operand is only dropped, as `x` is from `x * 0`, if it
+    /// [is plain](Self::is_plain), so that an error in it like a division by zero is not lost.
+    /// `0^0` becomes 1, as it evaluates to.
+    #[must_use]
+    #[allow(clippy::float_cmp)]
+    pub fn simplify(self) -> Self {
+        let (lhs, op, rhs) = match self {
+            Self::BinOp { lhs, op, rhs } => (lhs.simplify(), op, rhs.simplify()),
+            Self::UnOp { op, inner } => {
+                return Self::UnOp {
+                    op,
+                    inner: Box::new(inner.simplify()),
+                }
+            }
+            Self::Call { name, func, args } => {
+                return Self::Call {
+                    name,
+                    func,
+                    args: args.into_iter().map(Self::simplify).collect(),
+                }
+            }
+            Self::Bound {
+                form,
+                var,
+                args,
+                body,
+            } => {
+                return Self::Bound {
+                    form,
+                    var,
+                    args: args.into_iter().map(Self::simplify).collect(),
+                    body: Box::new(body.simplify()),
+                }
+            }
+            x @ (Self::Var(_) | Self::Num(_)) => return x,
+        };
+        let is = |x: &Self, value: f128| matches!(x, Self::Num(x) if *x == value);
+        let (mul, pow) = (
+            matches!(op, BinOp::Mul | BinOp::ImplicitMul),
+            matches!(op, BinOp::Pow),
+        );
+        if (matches!(op, BinOp::Add | BinOp::Sub) && is(&rhs, 0.0))
+            || ((mul || pow) && is(&rhs, 1.0))
+        {
+            lhs
+        } else if (matches!(op, BinOp::Add) && is(&lhs, 0.0)) || (mul && is(&lhs, 1.0)) {
+            rhs
+        } else if mul && ((is(&rhs, 0.0) && lhs.is_plain()) || (is(&lhs, 0.0) && rhs.is_plain())) {
+            Self::Num(0.0)
+        } else if pow && is(&rhs, 0.0) && lhs.is_plain() {
+            Self::Num(1.0)
+        } else {
+            Self::BinOp {
+                lhs: Box::new(lhs),
+                op,
+                rhs: Box::new(rhs),
+            }
+        }
     }
 }
 
-fn parse_atom(iter: &mut Peekable<impl Iterator<Item = &Lexeme>>) -> Result<Expression> {
-    Ok(match iter.next() {
-        Some(Lexeme::Token(Token {
-            ty: TokenType::Num,
-            text,
-        })) => Expression::Num(parse_num(text)?),
-        Some(Lexeme::Token(Token {
-            ty: TokenType::Id,
-            text,
-        })) => match &**text {
-            "sin" => Expression::func(|x| Ok(x.sin()), parse_arg(iter)?),
-            "cos" => Expression::func(|x| Ok(x.cos()), parse_arg(iter)?),
-            "tan" => Expression::func(|x| Ok(x.tan()), parse_arg(iter)?),
-            "sec" => Expression::func(|x| Ok(1.0 / x.cos()), parse_arg(iter)?),
-            "csc" => Expression::func(|x| Ok(1.0 / x.sin()), parse_arg(iter)?),
-            "cot" => Expression::func(|x| Ok(1.0 / x.tan()), parse_arg(iter)?),
-            "asin" | "arcsin" => Expression::func(|x| Ok(x.asin()), parse_arg(iter)?),
-            "acos" | "arccos" => Expression::func(|x| Ok(x.acos()), parse_arg(iter)?),
-            "atan" | "arctan" => Expression::func(|x| Ok(x.atan()), parse_arg(iter)?),
-            "asec" | "arcsec" => Expression::func(|x| Ok((1.0 / x).acos()), parse_arg(iter)?),
-            "acsc" | "arccsc" => Expression::func(|x| Ok((1.0 / x).asin()), parse_arg(iter)?),
-            "acot" | "arccot" => Expression::func(|x| Ok((1.0 / x).atan()), parse_arg(iter)?),
-            "loge" | "ln" => Expression::func(|x| Ok(x.ln()), parse_arg(iter)?),
-            "log10" | "log" => Expression::func(|x| Ok(x.log10()), parse_arg(iter)?),
-            "log2" | "lb" => Expression::func(|x| Ok(x.log2()), parse_arg(iter)?),
-            "sqrt" => Expression::func(|x| Ok(x.sqrt()), parse_arg(iter)?),
-            "cbrt" => Expression::func(|x| Ok(x.cbrt()), parse_arg(iter)?),
-            "abs" => Expression::func(|x| Ok(x.abs()), parse_arg(iter)?),
-            "e" => Expression::Num(core::f128::consts::E),
-            "pi" => Expression::Num(core::f128::consts::PI),
-            "tau" => Expression::Num(core::f128::consts::TAU),
-            _ => Err(Error::Unrecognized)?,
-        },
-        Some(Lexeme::Group(Group { inner })) => parse_bp(&mut inner.iter().peekable(), 0)?,
-        Some(Lexeme::Token(Token {
-            ty: TokenType::Sym,
-            text,
-        })) if text == "+" => Expression::UnOp {
-            op: UnOp::Pos,
-            inner: Box::new(parse_bp(iter, 7)?),
-        },
-        Some(Lexeme::Token(Token {
-            ty: TokenType::Sym,
-            text,
-        })) if text == "-" => Expression::UnOp {
-            op: UnOp::Neg,
-            inner: Box::new(parse_bp(iter, 7)?),
-        },
-        Some(Lexeme::Token(Token {
-            ty: TokenType::Sym,
-            text,
-        })) if ["*", "/", "^"].contains(&&**text) => Err(Error::Invalid)?,
-        _ => Err(Error::Unrecognized)?,
-    })
+/// A node of a parsed expression, labelled for display, so its grouping can be inspected
+pub struct TreeNode {
+    /// The operator, function, variable, or number at the node
+    pub label: String,
+    pub children: Vec<Self>,
 }
 
-fn parse_bp(iter: &mut Peekable<impl Iterator<Item = &Lexeme>>, min_bp: u8) -> Result<Expression> {
-    let mut lhs = parse_atom(iter)?;
+impl Expression {
+    /// The variables this reads that `env` does not define, in the order they first appear, so
+    /// `sin(x) + a*y` reads `["x", "a", "y"]`.
+    ///
+    /// The variable of a form like `sum` is defined within its body, so `sum(k, 1, n, k^2)` only
+    /// reads `n`. Constants and functions, built in or from `env`, are never counted.
+    #[must_use]
+    pub fn free_variables(&self, env: &Environment) -> Vec<String> {
+        let mut free = Vec::new();
+        self.collect_free(env, &mut Vec::new(), &mut free);
+        free
+    }
 
-    loop {
-        match iter.peek() {
-            None => break,
-            Some(Lexeme::Token(Token {
-                ty: TokenType::Sym,
-                text,
-            })) => {
-                let op = text;
-                let (l_bp, r_bp) = bin_bp(op);
-                if l_bp < min_bp {
-                    break;
+    /// Add the variables this reads that are neither in `bound` nor defined by `env` to `free`, in
+    /// the order they first appear
+    fn collect_free<'a>(
+        &'a self,
+        env: &Environment,
+        bound: &mut Vec<&'a str>,
+        free: &mut Vec<String>,
+    ) {
+        match self {
+            Self::BinOp { lhs, rhs, .. } => {
+                lhs.collect_free(env, bound, free);
+                rhs.collect_free(env, bound, free);
+            }
+            Self::UnOp { inner, .. } => inner.collect_free(env, bound, free),
+            Self::Call { args, .. } => {
+                for arg in args {
+                    arg.collect_free(env, bound, free);
                 }
-                iter.next();
-                let rhs = parse_bp(iter, r_bp)?;
-                lhs = Expression::BinOp {
+            }
+            Self::Bound {
+                var, args, body, ..
+            } => {
+                for arg in args {
+                    arg.collect_free(env, bound, free);
+                }
+                bound.push(var);
+                body.collect_free(env, bound, free);
+                bound.pop();
+            }
+            Self::Var(name) => {
+                let defined = bound.contains(&&**name)
+                    || env.variables.contains_key(name)
+                    || env.cells.contains_key(name)
+                    || math_constant(name).is_some()
+                    || physical_constant(name).is_some();
+                if !defined && !free.contains(name) {
+                    free.push(name.clone());
+                }
+            }
+            Self::Num(_) => {}
+        }
+    }
+
+    fn tree(&self) -> TreeNode {
+        let node = |label: String, children: &[&Self]| TreeNode {
+            label,
+            children: children.iter().map(|x| x.tree()).collect(),
+        };
+        match self {
+            Self::BinOp {
+                lhs,
+                op: BinOp::ImplicitMul,
+                rhs,
+            } => node("* (implied)".to_owned(), &[lhs, rhs]),
+            Self::BinOp { lhs, op, rhs } => node(op.to_string(), &[lhs, rhs]),
+            Self::UnOp {
+                op: UnOp::Fn { name, .. },
+                inner,
+            } => node(name.clone(), &[inner]),
+            Self::UnOp {
+                op: UnOp::Pos,
+                inner,
+            } => node("+ (sign)".to_owned(), &[inner]),
+            Self::UnOp {
+                op: UnOp::Neg,
+                inner,
+            } => node("- (sign)".to_owned(), &[inner]),
+            Self::Call { name, args, .. } => node(name.clone(), &args.iter().collect::<Vec<_>>()),
+            Self::Bound {
+                form,
+                var,
+                args,
+                body,
+            } => node(
+                format!("{} over {var}", form.name()),
+                &args.iter().chain([&**body]).collect::<Vec<_>>(),
+            ),
+            Self::Var(name) => node(name.clone(), &[]),
+            Self::Num(x) => node((*x as f64).to_string(), &[]),
+        }
+    }
+}
+
+/// Writes the expression with every operation parenthesized, showing exactly how it was grouped
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let write_args = |f: &mut fmt::Formatter, args: &[Self]| {
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{arg}")?;
+            }
+            Ok(())
+        };
+        match self {
+            Self::BinOp { lhs, op, rhs } => write!(f, "({lhs} {op} {rhs})"),
+            Self::UnOp {
+                op: UnOp::Fn { name, .. },
+                inner,
+            } => write!(f, "{name}({inner})"),
+            Self::UnOp {
+                op: UnOp::Pos,
+                inner,
+            } => write!(f, "(+{inner})"),
+            Self::UnOp {
+                op: UnOp::Neg,
+                inner,
+            } => write!(f, "(-{inner})"),
+            Self::Call { name, args, .. } => {
+                write!(f, "{name}(")?;
+                write_args(f, args)?;
+                write!(f, ")")
+            }
+            Self::Bound {
+                form,
+                var,
+                args,
+                body,
+            } if form.body_first() => {
+                write!(f, "{}({body}, {var}, ", form.name())?;
+                write_args(f, args)?;
+                write!(f, ")")
+            }
+            Self::Bound {
+                form,
+                var,
+                args,
+                body,
+            } => {
+                write!(f, "{}({var}, ", form.name())?;
+                write_args(f, args)?;
+                write!(f, ", {body})")
+            }
+            Self::Var(name) => write!(f, "{name}"),
+            Self::Num(x) => write!(f, "{}", *x as f64),
+        }
+    }
+}
+
+impl Expression {
+    /// Evaluate the expression, looking up variables in `bound` from innermost to outermost and
+    /// noting numerical pitfalls in `warnings`
+    #[allow(clippy::float_cmp)]
+    fn eval(
+        &self,
+        env: &Environment,
+        bound: &mut Vec<(String, f128)>,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<f128> {
+        Ok(match self {
+            Self::BinOp { lhs, op, rhs } => op.apply(
+                lhs.eval(env, bound, warnings)?,
+                rhs.eval(env, bound, warnings)?,
+                env,
+                warnings,
+            )?,
+            Self::UnOp { op, inner } => match op {
+                UnOp::Pos => inner.eval(env, bound, warnings)?,
+                UnOp::Neg => -inner.eval(env, bound, warnings)?,
+                UnOp::Fn { func, .. } => func(inner.eval(env, bound, warnings)?)?,
+            },
+            Self::Call { func, args, .. } => func(
+                &args
+                    .iter()
+                    .map(|x| x.eval(env, bound, warnings))
+                    .collect::<Result<Vec<_>>>()?,
+            )?,
+            Self::Bound {
+                form,
+                var,
+                args,
+                body,
+            } => {
+                let args = args
+                    .iter()
+                    .map(|x| x.eval(env, bound, warnings))
+                    .collect::<Result<Vec<_>>>()?;
+                bound.push((var.clone(), 0.0));
+                let mut discarded = Vec::new();
+                let warnings = if form.solves() {
+                    &mut discarded
+                } else {
+                    warnings
+                };
+                let result = form.eval(&args, |x| {
+                    check_deadline(env)?;
+                    if let Some(last) = bound.last_mut() {
+                        last.1 = x;
+                    }
+                    body.eval(env, bound, warnings)
+                });
+                bound.pop();
+                result?
+            }
+            Self::Var(name) => bound
+                .iter()
+                .rev()
+                .find(|(x, _)| x == name)
+                .map(|&(_, x)| x)
+                .or_else(|| env.variables.get(name).copied())
+                .map(Ok)
+                // A cell that failed fails the expression naming it
+                .or_else(|| env.cells.get(name).cloned())
+                .or_else(|| math_constant(name).map(Ok))
+                .or_else(|| physical_constant(name).map(Ok))
+                .ok_or_else(|| Error::UnknownIdentifier(name.clone()))??,
+            Self::Num(x) => *x,
+        })
+    }
+
+    /// Prepare the expression to be evaluated many times over, as for a table or plot, with each
+    /// of `params` bound to the value at its index in the slice it is called with.
+    ///
+    /// The variables are resolved once here, so that evaluating does not look them up by name:
+    /// those bound by a [`Self::Bound`] or in `params` get a slot in a buffer, and the rest are
+    /// read from `env` or the constants, as [`Self::eval`] reads them. The result is the same as
+    /// evaluating with `params` bound, but any warnings are dropped. A slice of another length
+    /// than `params` is an [`Error::ArgumentCount`].
+    pub fn compile<'a>(
+        &'a self,
+        env: &'a Environment,
+        params: &[&'a str],
+    ) -> impl Fn(&[Value]) -> Result<Value> + 'a {
+        let mut scope: Vec<&str> = params.to_vec();
+        let mut slots = scope.len();
+        let node = self.compile_node(env, &mut scope, &mut slots);
+        let (count, buffer) = (params.len(), RefCell::new(Vec::with_capacity(slots)));
+        move |args| {
+            if args.len() != count {
+                Err(Error::ArgumentCount {
+                    expected: count,
+                    found: args.len(),
+                    pos: 0,
+                })?;
+            }
+            let mut buffer = buffer.borrow_mut();
+            buffer.clear();
+            buffer.extend_from_slice(args);
+            buffer.resize(slots, 0.0);
+            node.eval(env, &mut buffer, &mut Vec::new())
+        }
+    }
+
+    /// The [`Node`] for the expression with `scope` holding the names of the slots bound around it,
+    /// counting the slots it needs into `slots`
+    fn compile_node<'a>(
+        &'a self,
+        env: &Environment,
+        scope: &mut Vec<&'a str>,
+        slots: &mut usize,
+    ) -> Node<'a> {
+        let mut compile = |x: &'a Self| Box::new(x.compile_node(env, scope, slots));
+        match self {
+            Self::BinOp { lhs, op, rhs } => Node::BinOp(op, compile(lhs), compile(rhs)),
+            Self::UnOp { op, inner } => Node::UnOp(op, compile(inner)),
+            Self::Call { func, args, .. } => Node::Call(
+                func,
+                args.iter()
+                    .map(|x| x.compile_node(env, scope, slots))
+                    .collect(),
+            ),
+            Self::Bound {
+                form,
+                var,
+                args,
+                body,
+            } => {
+                let args = args
+                    .iter()
+                    .map(|x| x.compile_node(env, scope, slots))
+                    .collect();
+                scope.push(var);
+                let slot = scope.len() - 1;
+                *slots = (*slots).max(scope.len());
+                let body = Box::new(body.compile_node(env, scope, slots));
+                scope.pop();
+                Node::Bound {
+                    form: *form,
+                    slot,
+                    args,
+                    body,
+                }
+            }
+            Self::Var(name) => scope.iter().rposition(|x| x == name).map_or_else(
+                || {
+                    Node::Const(
+                        env.variables
+                            .get(name)
+                            .copied()
+                            .map(Ok)
+                            .or_else(|| env.cells.get(name).cloned())
+                            .or_else(|| math_constant(name).map(Ok))
+                            .or_else(|| physical_constant(name).map(Ok))
+                            .unwrap_or_else(|| Err(Error::UnknownIdentifier(name.clone()))),
+                    )
+                },
+                Node::Slot,
+            ),
+            Self::Num(x) => Node::Const(Ok(*x)),
+        }
+    }
+}
+
+/// An [`Expression`] with its variables resolved to slots or values by [`Expression::compile`]
+enum Node<'a> {
+    BinOp(&'a BinOp, Box<Self>, Box<Self>),
+    UnOp(&'a UnOp, Box<Self>),
+    Call(&'a CallFn, Vec<Self>),
+    Bound {
+        form: BoundForm,
+        slot: usize,
+        args: Vec<Self>,
+        body: Box<Self>,
+    },
+    /// A variable bound in the slot at this index
+    Slot(usize),
+    /// A number, or a variable from the environment, which may have failed if it is a cell
+    Const(Result<Value>),
+}
+
+impl Node<'_> {
+    /// Evaluate the node with its variables' values in `slots`, as [`Expression::eval`] does
+    fn eval(
+        &self,
+        env: &Environment,
+        slots: &mut [Value],
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Value> {
+        Ok(match self {
+            Self::BinOp(op, lhs, rhs) => op.apply(
+                lhs.eval(env, slots, warnings)?,
+                rhs.eval(env, slots, warnings)?,
+                env,
+                warnings,
+            )?,
+            Self::UnOp(op, inner) => match op {
+                UnOp::Pos => inner.eval(env, slots, warnings)?,
+                UnOp::Neg => -inner.eval(env, slots, warnings)?,
+                UnOp::Fn { func, .. } => func(inner.eval(env, slots, warnings)?)?,
+            },
+            Self::Call(func, args) => func(
+                &args
+                    .iter()
+                    .map(|x| x.eval(env, slots, warnings))
+                    .collect::<Result<Vec<_>>>()?,
+            )?,
+            Self::Bound {
+                form,
+                slot,
+                args,
+                body,
+            } => {
+                let args = args
+                    .iter()
+                    .map(|x| x.eval(env, slots, warnings))
+                    .collect::<Result<Vec<_>>>()?;
+                let mut discarded = Vec::new();
+                let warnings = if form.solves() {
+                    &mut discarded
+                } else {
+                    warnings
+                };
+                form.eval(&args, |x| {
+                    check_deadline(env)?;
+                    slots[*slot] = x;
+                    body.eval(env, slots, warnings)
+                })?
+            }
+            Self::Slot(slot) => slots[*slot],
+            Self::Const(x) => x.clone()?,
+        })
+    }
+}
+
+/// Number of times a bound form evaluates its body between checks of [`Environment::deadline`]
+const DEADLINE_INTERVAL: u32 = 256;
+
+/// Count another evaluation of a bound form's body, failing with [`Error::Timeout`] if it is time
+/// to check [`Environment::deadline`] and it has passed
+fn check_deadline(env: &Environment) -> Result<()> {
+    let iterations = env.iterations.get().wrapping_add(1);
+    env.iterations.set(iterations);
+    // Reading the clock is slow next to evaluating a typical body
+    if iterations.is_multiple_of(DEADLINE_INTERVAL)
+        && env.deadline.is_some_and(|x| Instant::now() >= x)
+    {
+        return Err(Error::Timeout);
+    }
+    Ok(())
+}
+
+/// `lhs + rhs`, warning if the operands so nearly cancel out that rounding could swamp the sum
+#[allow(clippy::float_cmp)]
+fn add(lhs: f128, rhs: f128, warnings: &mut Vec<Warning>) -> f128 {
+    let sum = lhs + rhs;
+    if sum != 0.0 && sum.abs() < lhs.abs().max(rhs.abs()) * f128::from(f64::EPSILON) {
+        warn(warnings, Warning::PrecisionLoss);
+    }
+    sum
+}
+
+/// Add `warning` to `warnings` unless it is already there
+fn warn(warnings: &mut Vec<Warning>, warning: Warning) {
+    if !warnings.contains(&warning) {
+        warnings.push(warning);
+    }
+}
+
+/// Raise `base` to the power `exp`.
+///
+/// Whole-number exponents use repeated multiplication rather than `powf`, so that e.g. `10^2` is
+/// exactly 100. Zero to a negative power divides by zero, so it is an [`Error::DivByZero`] unless
+/// `ieee_division`, as for `/`. A negative base only has a real power if the exponent is a fraction
+/// with an odd denominator, like the cube root in `(-8)^(1/3)`; any other fractional power of it is
+/// an error.
+#[allow(clippy::float_cmp)]
+fn pow(base: f128, exp: f128, ieee_division: bool) -> Result<f128> {
+    if base == 0.0 && exp < 0.0 && !ieee_division {
+        Err(Error::DivByZero)?;
+    }
+    if exp.fract() == 0.0 && exp.abs() <= f128::from(i32::MAX) {
+        #[allow(clippy::cast_possible_truncation)]
+        return Ok(base.powi(exp as i32));
+    }
+    if base >= 0.0 || !exp.is_finite() {
+        return Ok(base.powf(exp));
+    }
+    (3..=MAX_ROOT_DENOMINATOR)
+        .step_by(2)
+        .map(f128::from)
+        .find(|&denom| {
+            let numer = exp * denom;
+            (numer.round() - numer).abs() < ROOT_TOLERANCE
+        })
+        .map(|denom| {
+            let magnitude = (-base).powf(exp);
+            // The numerator's parity decides the sign, e.g. `(-8)^(2/3)` is 4
+            if (exp * denom).round() % 2.0 == 0.0 {
+                magnitude
+            } else {
+                -magnitude
+            }
+        })
+        .ok_or(Error::DomainError)
+}
+
+/// Largest odd denominator `pow` recognizes in an exponent applied to a negative base
+const MAX_ROOT_DENOMINATOR: u8 = 99;
+
+/// How far an exponent times a candidate denominator may be from a whole number, covering the
+/// rounding in exponents like `1/3`
+const ROOT_TOLERANCE: f128 = 1e-20;
+
+/// `part` as a percentage of `whole`, which must not be zero
+#[allow(clippy::float_cmp)]
+fn percent(part: f128, whole: f128) -> Result<f128> {
+    if whole == 0.0 {
+        Err(Error::DomainError)
+    } else {
+        Ok(part / whole * 100.0)
+    }
+}
+
+/// Bitwise exclusive or of two whole numbers
+#[allow(clippy::float_cmp, clippy::cast_possible_truncation)]
+fn xor(lhs: f128, rhs: f128) -> Result<f128> {
+    let to_int = |x: f128| {
+        if x.fract() == 0.0 && x.abs() <= f128::from(u32::MAX) * f128::from(u32::MAX) {
+            Ok(x as i128)
+        } else {
+            Err(Error::DomainError)
+        }
+    };
+    Ok((to_int(lhs)? ^ to_int(rhs)?) as f128)
+}
+
+/// 2^113, up to which every whole number is exactly a [`Value`]; past it, some are skipped
+const MAX_EXACT: f128 = 10_384_593_717_069_655_257_060_992_658_440_192.0;
+
+/// `x` as an integer, if it is a whole number no larger in magnitude than [`MAX_EXACT`], so that
+/// integer arithmetic on it is exact
+#[allow(clippy::float_cmp, clippy::cast_possible_truncation)]
+fn exact_int(x: f128) -> Result<i128> {
+    if x.fract() == 0.0 && x.abs() <= MAX_EXACT {
+        Ok(x as i128)
+    } else {
+        Err(Error::DomainError)
+    }
+}
+
+/// Greatest common divisor of `a` and `b` by Euclid's algorithm
+const fn gcd_int(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Greatest common divisor of two whole numbers, which is never negative, and is 0 only when both
+/// are
+#[allow(clippy::cast_precision_loss)]
+fn gcd(a: f128, b: f128) -> Result<f128> {
+    Ok(gcd_int(exact_int(a)?.unsigned_abs(), exact_int(b)?.unsigned_abs()) as f128)
+}
+
+/// Least common multiple of two whole numbers, which is never negative, and is 0 when either is.
+///
+/// Multiples past [`MAX_EXACT`] are an error, since they could not be given exactly.
+#[allow(clippy::cast_precision_loss)]
+fn lcm(a: f128, b: f128) -> Result<f128> {
+    let (a, b) = (exact_int(a)?.unsigned_abs(), exact_int(b)?.unsigned_abs());
+    if a == 0 || b == 0 {
+        return Ok(0.0);
+    }
+    let multiple = (a / gcd_int(a, b))
+        .checked_mul(b)
+        .map(|x| x as f128)
+        .filter(|&x| x <= MAX_EXACT)
+        .ok_or(Error::DomainError)?;
+    Ok(multiple)
+}
+
+/// `a` modulo `b` for whole numbers, with the sign of `b` as in floored division, so `mod(-7, 3)`
+/// is 2
+#[allow(clippy::cast_precision_loss)]
+fn modulo(a: f128, b: f128) -> Result<f128> {
+    let (a, b) = (exact_int(a)?, exact_int(b)?);
+    if b == 0 {
+        Err(Error::DivByZero)?;
+    }
+    let remainder = a % b;
+    Ok(if remainder != 0 && (remainder < 0) != (b < 0) {
+        remainder + b
+    } else {
+        remainder
+    } as f128)
+}
+
+/// Most significant figures a [`Value`] holds; rounding to more leaves it unchanged
+const MAX_SIGNIFICANT_FIGURES: f128 = 34.0;
+
+/// `x` rounded to `figures` significant figures, a whole number of at least 1, so
+/// `sigfig(12345, 2)` is 12000 and `sigfig(0.0012345, 2)` is 0.0012
+#[allow(clippy::float_cmp, clippy::cast_possible_truncation)]
+fn significant_figures(x: f128, figures: f128) -> Result<f128> {
+    if figures.fract() != 0.0 || figures < 1.0 {
+        Err(Error::DomainError)?;
+    }
+    if x == 0.0 || !x.is_finite() || figures >= MAX_SIGNIFICANT_FIGURES {
+        return Ok(x);
+    }
+    // The digits to keep after the decimal point, negative to round to tens, hundreds, and so on;
+    // scaling by a power of ten, exact up to 10^48, keeps the rounding decimal
+    let places = figures as i32 - 1 - x.abs().log10().floor() as i32;
+    let scale = 10.0_f128.powi(places.abs());
+    if scale.is_infinite() {
+        // Only the tiniest values, which hold too few figures to round, get this far
+        return Ok(x);
+    }
+    Ok(if places < 0 {
+        (x / scale).round() * scale
+    } else {
+        (x * scale).round() / scale
+    })
+}
+
+/// Prefixes that mark a number in another base, in either case
+const RADIX_PREFIXES: [&str; 3] = ["0b", "0o", "0x"];
+
+/// Read `text`, a token at `pos`, as a decimal number.
+///
+/// Leading zeros are allowed and change nothing, so `0123` is 123 and `010` is 10, never 8 as in
+/// languages that read a leading zero as octal. Other bases need one of the [`RADIX_PREFIXES`]
+/// instead, which fail with [`Error::RadixPrefix`] rather than being mistaken for decimal.
+fn parse_num(text: &str, pos: usize) -> Result<f128> {
+    let invalid = || Error::InvalidNumber {
+        found: text.to_owned(),
+        pos,
+    };
+    let prefix = text.get(..2).unwrap_or_default();
+    if RADIX_PREFIXES
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(prefix))
+    {
+        Err(Error::RadixPrefix {
+            found: text.to_owned(),
+            pos,
+        })?;
+    }
+    let mut int_part = 0.0;
+    let mut chars = text.chars();
+    for c in &mut chars {
+        match c {
+            '0'..='9' => {
+                int_part *= 10.0;
+                int_part += f128::from(f64::from(c as u32 - '0' as u32));
+            }
+            '.' => break,
+            _ => Err(invalid())?,
+        }
+    }
+    let mut float_part = 0.0;
+    let mut multiplier = 0.1;
+    for c in &mut chars {
+        match c {
+            '0'..='9' => {
+                float_part += f128::from(f64::from(c as u32 - '0' as u32)) * multiplier;
+                multiplier /= 10.0;
+            }
+            _ => Err(invalid())?,
+        }
+    }
+    Ok(int_part + float_part)
+}
+
+fn parse_arg(
+    iter: &mut Peekable<impl Iterator<Item = &Lexeme>>,
+    env: &Environment,
+) -> Result<Expression> {
+    match iter.peek() {
+        Some(Lexeme::Group(_)) => parse_atom(iter, env),
+        _ => parse_bp(iter, JUXTAPOSITION_BP, env),
+    }
+}
+
+/// Split a parenthesized argument list on its commas, returning the position of its `(` too. Empty
+/// parentheses hold no arguments.
+fn split_args<'a>(
+    iter: &mut Peekable<impl Iterator<Item = &'a Lexeme>>,
+) -> Result<(usize, Vec<&'a [Lexeme]>)> {
+    let (inner, pos) = match iter.next() {
+        Some(Lexeme::Group(Group { inner, pos, .. })) => (inner, *pos),
+        x => Err(unexpected(x))?,
+    };
+    if inner.is_empty() {
+        return Ok((pos, Vec::new()));
+    }
+    let args = inner.split(|x| {
+        matches!(x, Lexeme::Token(Token {
+            ty: TokenType::Sym,
+            text,
+            ..
+        }) if text == ",")
+    });
+    Ok((pos, args.collect()))
+}
+
+/// Parse a parenthesized, comma-separated argument list of exactly `count` arguments
+fn parse_args(
+    iter: &mut Peekable<impl Iterator<Item = &Lexeme>>,
+    count: usize,
+    env: &Environment,
+) -> Result<Vec<Expression>> {
+    let (pos, args) = split_args(iter)?;
+    if args.len() != count {
+        Err(Error::ArgumentCount {
+            expected: count,
+            found: args.len(),
+            pos,
+        })?;
+    }
+    args.into_iter()
+        .map(|x| parse_bp(&mut x.iter().peekable(), 0, env))
+        .collect()
+}
+
+/// Parse the argument list of a bound form: the variable, `count` arguments, and then the body, or
+/// for forms that put the body first, the body, the variable, and then the arguments
+fn parse_bound(
+    iter: &mut Peekable<impl Iterator<Item = &Lexeme>>,
+    form: BoundForm,
+    count: usize,
+    env: &Environment,
+) -> Result<Expression> {
+    let (pos, args) = split_args(iter)?;
+    if args.len() != count + 2 {
+        Err(Error::ArgumentCount {
+            expected: count + 2,
+            found: args.len(),
+            pos,
+        })?;
+    }
+    let (var, args, body) = if form.body_first() {
+        (args[1], &args[2..], args[0])
+    } else {
+        (args[0], &args[1..=count], args[count + 1])
+    };
+    let var = bound_var(var)?;
+    Ok(Expression::Bound {
+        form,
+        var: var.clone(),
+        args: args
+            .iter()
+            .map(|x| parse_bp(&mut x.iter().peekable(), 0, env))
+            .collect::<Result<_>>()?,
+        body: Box::new(parse_bp(&mut body.iter().peekable(), 0, env)?),
+    })
+}
+
+/// The name in a bound form's variable argument, which must be a lone identifier
+fn bound_var(arg: &[Lexeme]) -> Result<&String> {
+    match arg {
+        [Lexeme::Token(Token {
+            ty: TokenType::Id,
+            text,
+            ..
+        })] => Ok(text),
+        _ => Err(unexpected(arg.get(1).or_else(|| arg.first()))),
+    }
+}
+
+/// Seconds since the start of 1970 in UTC, the Unix epoch, as `now()` gives them
+fn unix_time() -> f128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0.0, |x| f128::from(x.as_secs_f64()))
+}
+
+/// The mathematical constant called `name`, looked up only once no bound or user variable has the
+/// name
+fn math_constant(name: &str) -> Option<Value> {
+    Some(match name {
+        "e" => core::f128::consts::E,
+        "pi" | "π" => core::f128::consts::PI,
+        "tau" => core::f128::consts::TAU,
+        _ => return None,
+    })
+}
+
+/// The physical constant called `name`, in SI units, from CODATA 2018.
+///
+/// These are looked up only once no bound or user variable has the name, since many of them are
+/// single letters that are just as likely to be variables.
+fn physical_constant(name: &str) -> Option<Value> {
+    Some(match name {
+        // Speed of light in vacuum, m/s (exact)
+        "c" => 299_792_458.0,
+        // Planck constant, J s (exact)
+        "h" => 6.626_070_15e-34,
+        // Reduced Planck constant, J s
+        "hbar" => 6.626_070_15e-34 / core::f128::consts::TAU,
+        // Avogadro constant, 1/mol (exact)
+        "Na" => 6.022_140_76e23,
+        // Boltzmann constant, J/K (exact)
+        "kB" => 1.380_649e-23,
+        // Molar gas constant, J/(mol K), which is `Na * kB` (exact)
+        "R" => 6.022_140_76e23 * 1.380_649e-23,
+        // Elementary charge, C (exact)
+        "qe" => 1.602_176_634e-19,
+        // Newtonian constant of gravitation, m^3/(kg s^2), ± 0.000 15e-11
+        "G" => 6.674_30e-11,
+        // Electron mass, kg, ± 0.000 000 0028e-31
+        "me" => 9.109_383_701_5e-31,
+        // Proton mass, kg, ± 0.000 000 000 51e-27
+        "mp" => 1.672_621_923_69e-27,
+        _ => return None,
+    })
+}
+
+/// The built-in single-argument function called `name`, in the angle unit and `log` base of `env`
+fn unary_builtin(name: &str, env: &Environment) -> Option<UnaryFn> {
+    let angle = env.angle_mode;
+    Some(match name {
+        "sin" => Box::new(move |x| Ok(angle.to_radians(x).sin())),
+        "cos" => Box::new(move |x| Ok(angle.to_radians(x).cos())),
+        "tan" => Box::new(move |x| Ok(angle.to_radians(x).tan())),
+        "sec" => Box::new(move |x| Ok(1.0 / angle.to_radians(x).cos())),
+        "csc" => Box::new(move |x| Ok(1.0 / angle.to_radians(x).sin())),
+        "cot" => Box::new(move |x| Ok(1.0 / angle.to_radians(x).tan())),
+        "asin" | "arcsin" => Box::new(move |x| Ok(angle.radians_to_unit(x.asin()))),
+        "acos" | "arccos" => Box::new(move |x| Ok(angle.radians_to_unit(x.acos()))),
+        "atan" | "arctan" => Box::new(move |x| Ok(angle.radians_to_unit(x.atan()))),
+        "asec" | "arcsec" => Box::new(move |x| Ok(angle.radians_to_unit((1.0 / x).acos()))),
+        "acsc" | "arccsc" => Box::new(move |x| Ok(angle.radians_to_unit((1.0 / x).asin()))),
+        "acot" | "arccot" => Box::new(move |x| Ok(angle.radians_to_unit((1.0 / x).atan()))),
+        "loge" | "ln" => Box::new(|x| Ok(x.ln())),
+        "log" if env.log_base == LogBase::Natural => Box::new(|x| Ok(x.ln())),
+        "log10" | "log" => Box::new(|x| Ok(x.log10())),
+        "log2" | "lb" => Box::new(|x| Ok(x.log2())),
+        "sqrt" => Box::new(|x| Ok(x.sqrt())),
+        "cbrt" => Box::new(|x| Ok(x.cbrt())),
+        "abs" => Box::new(|x| Ok(x.abs())),
+        _ => None?,
+    })
+}
+
+/// Parse the identifier `name`, along with any arguments it takes
+fn parse_id(
+    name: &str,
+    iter: &mut Peekable<impl Iterator<Item = &Lexeme>>,
+    env: &Environment,
+) -> Result<Expression> {
+    if env.variables.contains_key(name) {
+        return Ok(Expression::Var(name.to_owned()));
+    }
+    if let Some(func) = env.functions.get(name) {
+        let func = Rc::clone(func);
+        return Ok(Expression::func(
+            name,
+            move |x| func(x),
+            parse_arg(iter, env)?,
+        ));
+    }
+    if let Some(func) = unary_builtin(name, env) {
+        return Ok(Expression::func(name, func, parse_arg(iter, env)?));
+    }
+    Ok(match name {
+        "now" => Expression::call(name, |_| Ok(unix_time()), parse_args(iter, 0, env)?),
+        "pow" => {
+            let ieee_division = env.ieee_division;
+            Expression::call(
+                name,
+                move |x| pow(x[0], x[1], ieee_division),
+                parse_args(iter, 2, env)?,
+            )
+        }
+        "pctchange" => Expression::call(
+            name,
+            |x| percent(x[1] - x[0], x[0]),
+            parse_args(iter, 2, env)?,
+        ),
+        "pctof" => Expression::call(name, |x| percent(x[0], x[1]), parse_args(iter, 2, env)?),
+        "gcd" => Expression::call(name, |x| gcd(x[0], x[1]), parse_args(iter, 2, env)?),
+        "lcm" => Expression::call(name, |x| lcm(x[0], x[1]), parse_args(iter, 2, env)?),
+        "mod" => Expression::call(name, |x| modulo(x[0], x[1]), parse_args(iter, 2, env)?),
+        "sigfig" => Expression::call(
+            name,
+            |x| significant_figures(x[0], x[1]),
+            parse_args(iter, 2, env)?,
+        ),
+        "sum" => parse_bound(
+            iter,
+            BoundForm::Sum {
+                limit: env.summation_limit,
+            },
+            2,
+            env,
+        )?,
+        "prod" => parse_bound(
+            iter,
+            BoundForm::Prod {
+                limit: env.summation_limit,
+            },
+            2,
+            env,
+        )?,
+        "integ" => parse_bound(
+            iter,
+            BoundForm::Integ {
+                steps: env.integration_steps,
+            },
+            2,
+            env,
+        )?,
+        "deriv" => parse_bound(iter, BoundForm::Deriv, 1, env)?,
+        "root" => parse_bound(iter, BoundForm::Root, 2, env)?,
+        "minimize" => parse_bound(iter, BoundForm::Minimize, 2, env)?,
+        // Constants are variables too, so a form's variable can shadow them, as `e` does in
+        // `sum(e, 1, 3, e)`
+        x => Expression::Var(x.to_owned()),
+    })
+}
+
+fn parse_atom(
+    iter: &mut Peekable<impl Iterator<Item = &Lexeme>>,
+    env: &Environment,
+) -> Result<Expression> {
+    Ok(match iter.next() {
+        Some(Lexeme::Token(Token {
+            ty: TokenType::Num,
+            text,
+            pos,
+        })) => Expression::Num(parse_num(text, *pos)?),
+        Some(Lexeme::Token(Token {
+            ty: TokenType::Id,
+            text,
+            ..
+        })) => parse_id(text, iter, env)?,
+        Some(Lexeme::Group(Group { inner, .. })) => {
+            let mut expr = parse_bp(&mut inner.iter().peekable(), 0, env)?;
+            // Parentheses make a product's grouping explicit
+            if let Expression::BinOp {
+                op: op @ BinOp::ImplicitMul,
+                ..
+            } = &mut expr
+            {
+                *op = BinOp::Mul;
+            }
+            expr
+        }
+        Some(Lexeme::Token(Token {
+            ty: TokenType::Sym,
+            text,
+            ..
+        })) if text == "+" => Expression::UnOp {
+            op: UnOp::Pos,
+            inner: Box::new(parse_bp(iter, 9, env)?),
+        },
+        Some(Lexeme::Token(Token {
+            ty: TokenType::Sym,
+            text,
+            ..
+        })) if text == "-" => Expression::UnOp {
+            op: UnOp::Neg,
+            inner: Box::new(parse_bp(iter, 9, env)?),
+        },
+        x => Err(unexpected(x))?,
+    })
+}
+
+fn parse_bp(
+    iter: &mut Peekable<impl Iterator<Item = &Lexeme>>,
+    min_bp: u8,
+    env: &Environment,
+) -> Result<Expression> {
+    let mut lhs = parse_atom(iter, env)?;
+
+    loop {
+        match iter.peek() {
+            None => break,
+            Some(
+                x @ Lexeme::Token(Token {
+                    ty: TokenType::Sym,
+                    text,
+                    ..
+                }),
+            ) => {
+                // Commas and assignment operators have no precedence, so they end up here too
+                let Some(operator) = Operator::find(text, env) else {
+                    Err(x.unexpected())?
+                };
+                let (l_bp, r_bp) = operator.bp;
+                if l_bp < min_bp {
+                    break;
+                }
+                iter.next();
+                let rhs = parse_bp(iter, r_bp, env)?;
+                lhs = Expression::BinOp {
                     lhs: Box::new(lhs),
-                    op: match &**op {
-                        "+" => BinOp::Add,
-                        "-" => BinOp::Sub,
-                        "*" => BinOp::Mul,
-                        "/" => BinOp::Div,
-                        "^" | "**" => BinOp::Pow,
-                        _ => unreachable!(),
-                    },
+                    op: operator.op,
                     rhs: Box::new(rhs),
                 }
             }
             _ => {
                 lhs = Expression::BinOp {
                     lhs: Box::new(lhs),
-                    op: BinOp::Mul,
-                    rhs: Box::new(parse_arg(iter)?),
+                    op: BinOp::ImplicitMul,
+                    rhs: Box::new(parse_arg(iter, env)?),
                 };
             }
         }
@@ -334,15 +2067,1790 @@ fn parse_bp(iter: &mut Peekable<impl Iterator<Item = &Lexeme>>, min_bp: u8) -> R
     Ok(lhs)
 }
 
-fn parse(text: &str) -> Result<Expression> {
-    let lexed = lex(&mut text.chars().peekable(), '\0')?;
-    parse_bp(&mut lexed.iter().peekable(), 0)
+/// Whether `c` is a character that renders as nothing, such as a byte order mark or a zero-width
+/// space, which sneak in through pasting and importing and should not affect evaluation
+const fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{feff}' | '\u{200b}'..='\u{200d}' | '\u{2060}')
+}
+
+/// Lex all of `text`, failing with [`Error::Empty`] if there is nothing in it, and with
+/// [`Error::TooLong`] if there is too much
+fn lex_text(text: &str) -> Result<Vec<Lexeme>> {
+    // Each opening bracket is a group, which lexing recurses into, so too many are turned away
+    // before lexing rather than counted after it
+    if text.len() > MAX_EXPRESSION_LEN || text.matches(['(', '[', '{']).count() > MAX_LEXEMES {
+        Err(Error::TooLong)?;
+    }
+    let lexed = lex(
+        &mut text
+            .char_indices()
+            .filter(|&(_, c)| !is_invisible(c))
+            .peekable(),
+        None,
+    )?;
+    if lexed.is_empty() {
+        Err(Error::Empty)?;
+    }
+    if lexeme_count(&lexed) > MAX_LEXEMES {
+        Err(Error::TooLong)?;
+    }
+    Ok(lexed)
+}
+
+/// Number of tokens and groups in `lexed`, counting those inside groups too
+fn lexeme_count(lexed: &[Lexeme]) -> usize {
+    lexed
+        .iter()
+        .map(|x| match x {
+            Lexeme::Token(_) => 1,
+            Lexeme::Group(Group { inner, .. }) => 1 + lexeme_count(inner),
+        })
+        .sum()
+}
+
+/// Evaluate `lexed` directly if it is plain arithmetic: numbers alternating with binary operators,
+/// without parentheses, names, or unary operators.
+///
+/// This is the most common kind of expression, and evaluating it with a shunting-yard loop skips
+/// building an `Expression` tree. The operators apply in the same order as they would in the tree,
+/// so the result is identical. Anything else, including malformed input, gives `None`, leaving it
+/// to the parser.
+fn eval_arithmetic(
+    lexed: &[Lexeme],
+    env: &Environment,
+    warnings: &mut Vec<Warning>,
+) -> Option<Result<f128>> {
+    let mut numbers = Vec::with_capacity(lexed.len() / 2 + 1);
+    let mut ops = Vec::with_capacity(lexed.len() / 2);
+    for (i, x) in lexed.iter().enumerate() {
+        match x {
+            Lexeme::Token(Token {
+                ty: TokenType::Num,
+                text,
+                pos,
+            }) if i % 2 == 0 => numbers.push((text, *pos)),
+            Lexeme::Token(Token {
+                ty: TokenType::Sym,
+                text,
+                ..
+            }) if i % 2 == 1 => ops.push(Operator::find(text, env)?),
+            _ => return None,
+        }
+    }
+    if numbers.len() != ops.len() + 1 {
+        return None;
+    }
+    // Numbers are all parsed first, as the parser would, so that the same error comes first
+    let numbers = match numbers
+        .into_iter()
+        .map(|(x, pos)| parse_num(x, pos))
+        .collect()
+    {
+        Ok(x) => x,
+        Err(x) => return Some(Err(x)),
+    };
+    Some(shunting_yard(numbers, &ops, env, warnings))
+}
+
+/// Evaluate `numbers` joined by the binary operators `ops`, respecting their binding powers. There
+/// must be one more number than there are operators.
+fn shunting_yard(
+    numbers: Vec<f128>,
+    ops: &[&Operator],
+    env: &Environment,
+    warnings: &mut Vec<Warning>,
+) -> Result<f128> {
+    if numbers.len() != ops.len() + 1 {
+        return Err(Error::UnexpectedEnd);
+    }
+    let mut reduce = |values: &mut Vec<f128>, operator: &Operator| {
+        let (Some(rhs), Some(lhs)) = (values.pop(), values.pop()) else {
+            return Err(Error::UnexpectedEnd);
+        };
+        values.push(operator.op.apply(lhs, rhs, env, warnings)?);
+        Ok(())
+    };
+    let mut numbers = numbers.into_iter();
+    let mut values = Vec::with_capacity(ops.len() + 1);
+    values.extend(numbers.next());
+    let mut pending: Vec<&Operator> = Vec::with_capacity(ops.len());
+    for (&op, number) in ops.iter().zip(numbers) {
+        let (l_bp, _) = op.bp;
+        while let Some(top) = pending.pop_if(|top| l_bp < top.bp.1) {
+            reduce(&mut values, top)?;
+        }
+        pending.push(op);
+        values.push(number);
+    }
+    while let Some(op) = pending.pop() {
+        reduce(&mut values, op)?;
+    }
+    values.pop().ok_or(Error::UnexpectedEnd)
+}
+
+fn parse(text: &str, env: &Environment) -> Result<Expression> {
+    parse_bp(&mut lex_text(text)?.iter().peekable(), 0, env)
 }
 
-/// Evaluate the input expression
+/// Operators that assign to the variable on their left
+const ASSIGN_OPS: [&str; 5] = ["=", "+=", "-=", "*=", "/="];
+
+/// Evaluate the input expression with the default environment
+///
+/// # Errors
+/// Returns an error upon receiving either an invalid expression or encountering an unknown operator
+pub fn evaluate(text: &str) -> Result<Value> {
+    eval_with(text, &Environment::default())
+}
+
+/// Evaluate the input expression, giving up if it takes longer than `timeout`
+///
+/// # Errors
+/// Returns [`Error::Timeout`] if the time runs out, or else any error [`evaluate`] would
+pub fn evaluate_with_timeout(text: &str, timeout: Duration) -> Result<Value> {
+    let env = Environment {
+        deadline: Some(Instant::now() + timeout),
+        ..Environment::default()
+    };
+    eval_with(text, &env)
+}
+
+/// Evaluate the input expression, resolving names and settings through `env`
+///
+/// # Errors
+/// Returns an error upon receiving either an invalid expression or encountering an unknown operator
+pub fn eval_with(text: &str, env: &Environment) -> Result<Value> {
+    eval_with_warnings(text, env).map(|(x, _)| x)
+}
+
+/// Like [`eval_with`], but also returns any numerical pitfalls met along the way, which do not stop
+/// evaluation
 ///
 /// # Errors
 /// Returns an error upon receiving either an invalid expression or encountering an unknown operator
-pub fn evaluate(text: &str) -> Result<f128> {
-    parse(text)?.eval()
+pub fn eval_with_warnings(text: &str, env: &Environment) -> Result<(Value, Vec<Warning>)> {
+    let mut warnings = Vec::new();
+    let lexed = lex_statement(text, env, &mut warnings)?;
+    let value = eval_statement(&lexed, env, &mut warnings)?;
+    Ok(check_result(value, warnings, env))
+}
+
+/// Lex `text`, which under [`Environment::lenient_trailing`] ends before any character that cannot
+/// be lexed
+fn lex_statement(
+    text: &str,
+    env: &Environment,
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<Lexeme>> {
+    match lex_text(text) {
+        Err(Error::UnrecognizedChar { pos, .. } | Error::UnbalancedParen(pos))
+            if env.lenient_trailing && pos > 0 =>
+        {
+            let lexed = lex_text(&text[..pos])?;
+            warn(warnings, Warning::IgnoredTrailing);
+            Ok(lexed)
+        }
+        result => result,
+    }
+    .inspect(|lexed| {
+        if env.strict_identifiers && multiplies_name(lexed, env) {
+            warn(warnings, Warning::MultipliedName);
+        }
+    })
+}
+
+/// Whether `lexed` has a variable or constant directly followed by a parenthesized group, which
+/// is read as multiplication, as in `x(2)` but not `x (2)`
+fn multiplies_name(lexed: &[Lexeme], env: &Environment) -> bool {
+    lexed.windows(2).any(|pair| match pair {
+        [Lexeme::Token(Token {
+            ty: TokenType::Id,
+            text,
+            pos,
+        }), Lexeme::Group(group)] => {
+            pos + text.len() == group.pos
+                && (env.variables.contains_key(text)
+                    || (!env.functions.contains_key(text)
+                        && (math_constant(text).is_some() || physical_constant(text).is_some())))
+        }
+        _ => false,
+    }) || lexed.iter().any(|x| match x {
+        Lexeme::Group(group) => multiplies_name(&group.inner, env),
+        Lexeme::Token(_) => false,
+    })
+}
+
+/// Evaluate `lexed` as an expression, which under [`Environment::lenient_trailing`] ends where the
+/// longest readable expression at its start does. Only text juxtaposed after a complete operand is
+/// left out, like `seconds` in `2 + 3 seconds`, so `2 * y` is still an error rather than `2`.
+fn eval_statement(
+    lexed: &[Lexeme],
+    env: &Environment,
+    warnings: &mut Vec<Warning>,
+) -> Result<f128> {
+    let mut attempt = Vec::new();
+    let mut result = eval_lexemes(lexed, env, &mut attempt);
+    if env.lenient_trailing && result.as_ref().is_err_and(Error::is_unreadable) {
+        for end in (1..lexed.len()).rev() {
+            if let Lexeme::Token(Token {
+                ty: TokenType::Sym, ..
+            }) = lexed[end]
+            {
+                continue;
+            }
+            let mut shorter = Vec::new();
+            match eval_lexemes(&lexed[..end], env, &mut shorter) {
+                Err(x) if x.is_unreadable() => {}
+                shorter_result => {
+                    (result, attempt) = (shorter_result, shorter);
+                    warn(&mut attempt, Warning::IgnoredTrailing);
+                    break;
+                }
+            }
+        }
+    }
+    for x in attempt {
+        warn(warnings, x);
+    }
+    result
+}
+
+/// Evaluate `lexed` as an expression
+fn eval_lexemes(lexed: &[Lexeme], env: &Environment, warnings: &mut Vec<Warning>) -> Result<f128> {
+    match eval_arithmetic(lexed, env, warnings) {
+        Some(x) => x,
+        None => {
+            parse_bp(&mut lexed.iter().peekable(), 0, env)?.eval(env, &mut Vec::new(), warnings)
+        }
+    }
+}
+
+/// Add the warnings that apply to `value` as a final result
+fn check_result(
+    value: Value,
+    mut warnings: Vec<Warning>,
+    env: &Environment,
+) -> (Value, Vec<Warning>) {
+    if value.is_nan() {
+        warn(&mut warnings, Warning::NotFinite);
+    } else if value.is_infinite() || (!env.extended_digits && (value as f64).is_infinite()) {
+        // Results are displayed as `f64` unless extended, which overflows before `Value` does
+        warn(&mut warnings, Warning::Overflow);
+    }
+    (value, warnings)
+}
+
+/// Evaluate `text`, which contains `±`, once with every `±` as `+` and once with every `±` as `-`,
+/// like `(-b ± sqrt(b^2 - 4*a*c)) / (2*a)` for both roots of a quadratic.
+///
+/// Several `±` all take the same sign rather than each giving its own pair of results, so there
+/// are always two. Returns `None` if `text` has no `±` or cannot be lexed.
+#[must_use]
+pub fn eval_plus_minus(text: &str, env: &Environment) -> Option<Result<(Value, Value)>> {
+    let mut warnings = Vec::new();
+    let lexed = lex_statement(text, env, &mut warnings).ok()?;
+    let plus = choose_sign(&lexed, "+");
+    if plus.0.is_empty() || plus.1 == 0 {
+        return None;
+    }
+    let minus = choose_sign(&lexed, "-");
+    let [plus, minus] = [plus, minus].map(|x| eval_statement(&x.0, env, &mut warnings));
+    Some(plus.and_then(|plus| Ok((plus, minus?))))
+}
+
+/// A copy of `lexed` with each `±` in it replaced by `sign`, and the number of them
+fn choose_sign(lexed: &[Lexeme], sign: &str) -> (Vec<Lexeme>, usize) {
+    let mut count = 0;
+    let chosen = lexed
+        .iter()
+        .map(|x| match x {
+            Lexeme::Token(token) if token.text == "±" => {
+                count += 1;
+                Lexeme::Token(Token {
+                    text: sign.into(),
+                    ..token.clone()
+                })
+            }
+            Lexeme::Group(group) => {
+                let (inner, inner_count) = choose_sign(&group.inner, sign);
+                count += inner_count;
+                Lexeme::Group(Group {
+                    inner,
+                    open: group.open,
+                    pos: group.pos,
+                })
+            }
+            x @ Lexeme::Token(_) => x.clone(),
+        })
+        .collect();
+    (chosen, count)
+}
+
+/// The fully parenthesized form of `text` if its grouping is easy to misread, like `1/2 x`, which
+/// is `(1 / (2 * x))`
+pub fn precedence_hint(text: &str, env: &Environment) -> Option<String> {
+    parse(text, env)
+        .ok()
+        .filter(Expression::is_ambiguous)
+        .map(|x| x.to_string())
+}
+
+/// The variables the statement `text` needs that `env` does not define, as
+/// [`Expression::free_variables`] finds them for its expression, for when there is only text.
+///
+/// A variable being assigned is not needed, so `x = y + 1` only needs `y`, unless a compound
+/// assignment like `x += 1` reads it first.
+///
+/// # Errors
+/// Returns an error if the expression cannot be parsed; it is not evaluated
+pub fn free_variables(text: &str, env: &Environment) -> Result<Vec<String>> {
+    let lexed = lex_text(text)?;
+    let (targets, rest) = split_assignments(&lexed);
+    let expression = parse_bp(&mut rest.iter().peekable(), 0, env)?;
+    let compound = targets
+        .into_iter()
+        .filter(|&(name, op)| op != "=" && !env.variables.contains_key(name))
+        .map(|(name, _)| name.clone());
+    let mut free = Vec::new();
+    for name in compound.chain(expression.free_variables(env)) {
+        if !free.contains(&name) {
+            free.push(name);
+        }
+    }
+    Ok(free)
+}
+
+/// The parse tree of the expression in the statement `text`, leaving out any assignments to it
+///
+/// # Errors
+/// Returns an error if the expression cannot be parsed; it is not evaluated
+pub fn parse_tree(text: &str, env: &Environment) -> Result<TreeNode> {
+    parse_str(text, env).map(|x| x.tree()).map_err(|x| x.error)
+}
+
+/// Parse the expression in the statement `text` by the grammar in the [module docs](self),
+/// leaving out any assignments to it. This is the one way in to the parser.
+///
+/// # Errors
+/// Returns where and why the expression cannot be parsed; it is not evaluated, so names that are
+/// not defined are not errors
+pub fn parse_str(text: &str, env: &Environment) -> core::result::Result<Expression, ParseError> {
+    parse_statement(text, env).map_err(|x| ParseError::new(x, text))
+}
+
+/// Parse the expression in the statement `text` for [`parse_str`]
+fn parse_statement(text: &str, env: &Environment) -> Result<Expression> {
+    let lexed = lex_text(text)?;
+    parse_bp(&mut split_assignments(&lexed).1.iter().peekable(), 0, env)
+}
+
+/// Split the statement `lexed` into the variables it assigns to, each with its assignment operator,
+/// and the expression whose value is assigned
+fn split_assignments(lexed: &[Lexeme]) -> (Vec<(&String, &String)>, &[Lexeme]) {
+    let mut targets = Vec::new();
+    let mut rest = lexed;
+    while let [Lexeme::Token(Token {
+        ty: TokenType::Id,
+        text: name,
+        ..
+    }), Lexeme::Token(Token {
+        ty: TokenType::Sym,
+        text: op,
+        ..
+    }), tail @ ..] = rest
+    {
+        if !ASSIGN_OPS.contains(&&**op) {
+            break;
+        }
+        targets.push((name, op));
+        rest = tail;
+    }
+    (targets, rest)
+}
+
+/// Evaluate each line of `text` as a statement, in order, so that later lines see the variables
+/// earlier ones assign.
+///
+/// Blank lines and prose give `None`. A line is prose if it cannot be read as an expression or if
+/// it names anything undefined, since a sentence like `see the notes` reads as a product of unknown
+/// variables. Any other line gives its result, which is an error only for math that is well formed
+/// but cannot be evaluated, such as a division by zero.
+pub fn evaluate_document(text: &str, env: &mut Environment) -> Vec<Option<Result<Value>>> {
+    text.lines()
+        .map(|line| match execute(line, env) {
+            Err(x) if x.is_unreadable() => None,
+            result => Some(result),
+        })
+        .collect()
+}
+
+/// Whether `name` names a cell: column letters followed by a row number, like `A1` or `AB12`
+#[must_use]
+pub fn is_cell_name(name: &str) -> bool {
+    let row = name.trim_start_matches(|x: char| x.is_ascii_uppercase());
+    row.len() < name.len() && !row.is_empty() && row.bytes().all(|x| x.is_ascii_digit())
+}
+
+/// If `line` defines a cell, like `A2: A1 * 2`, the cell's name and its formula
+#[must_use]
+pub fn cell_definition(line: &str) -> Option<(&str, &str)> {
+    let (name, formula) = line.split_once(':')?;
+    let name = name.trim();
+    is_cell_name(name).then(|| (name, formula.trim()))
+}
+
+/// The cells that `formula` names, in order, or none if it cannot be read
+#[must_use]
+pub fn cell_references(formula: &str) -> Vec<String> {
+    fn collect(lexed: &[Lexeme], cells: &mut Vec<String>) {
+        for x in lexed {
+            match x {
+                Lexeme::Token(Token {
+                    ty: TokenType::Id,
+                    text,
+                    ..
+                }) if is_cell_name(text) && !cells.contains(text) => cells.push(text.clone()),
+                Lexeme::Token(_) => {}
+                Lexeme::Group(group) => collect(&group.inner, cells),
+            }
+        }
+    }
+    let mut cells = Vec::new();
+    if let Ok(lexed) = lex_text(formula) {
+        collect(&lexed, &mut cells);
+    }
+    cells
+}
+
+/// Evaluate the cells defined by `cells`, pairs of a name and a formula as given by
+/// [`cell_definition`], in whatever order their references need.
+///
+/// A formula can name cells defined after it, and sees them as [`Environment::cells`]. Cells that
+/// refer to each other in a circle fail with [`Error::CircularReference`], as does any cell that
+/// refers to one of them. A cell defined twice takes its last formula.
+#[must_use]
+pub fn evaluate_cells(cells: &[(&str, &str)], env: &Environment) -> HashMap<String, Result<Value>> {
+    let formulas: HashMap<&str, &str> = cells.iter().copied().collect();
+    let mut values = HashMap::new();
+    for name in formulas.keys() {
+        let _ = evaluate_cell(name, &formulas, env, &mut values, &mut Vec::new());
+    }
+    values
+}
+
+/// Evaluate the cell `name` for [`evaluate_cells`], after the cells it refers to, unless it is
+/// already in `values` or is one of `resolving`, the cells waiting on it
+fn evaluate_cell<'a>(
+    name: &'a str,
+    formulas: &HashMap<&'a str, &'a str>,
+    env: &Environment,
+    values: &mut HashMap<String, Result<Value>>,
+    resolving: &mut Vec<&'a str>,
+) -> Result<Value> {
+    if let Some(value) = values.get(name) {
+        return value.clone();
+    }
+    if resolving.contains(&name) {
+        Err(Error::CircularReference(name.to_owned()))?;
+    }
+    resolving.push(name);
+    let formula = formulas[name];
+    let mut scope = env.clone();
+    for reference in cell_references(formula) {
+        if let Some((&reference, _)) = formulas.get_key_value(&*reference) {
+            let value = evaluate_cell(reference, formulas, env, values, resolving);
+            scope.cells.insert(reference.to_owned(), value);
+        }
+    }
+    resolving.pop();
+    let value = execute(formula, &mut scope);
+    values.insert(name.to_owned(), value.clone());
+    value
+}
+
+/// A row of a table: a value of the variable and the body's value there
+pub type TableRow = (Value, Result<Value>);
+
+/// Evaluate `text` if it is a table, like `table(x, 0, 5, x^2)` or `table(x, 0, 1, 0.25, sin(x))`.
+///
+/// There is a row for each value of the variable from the first bound to the second, in steps of
+/// the optional fourth argument or 1.
+///
+/// Returns `None` if `text` is not a table. A row whose body fails holds the error, while invalid
+/// bounds, or more rows than [`Environment::summation_limit`], fail the whole table.
+#[must_use]
+pub fn table(text: &str, env: &Environment) -> Option<Result<Vec<TableRow>>> {
+    let lexed = lex_text(text).ok()?;
+    let [Lexeme::Token(Token {
+        ty: TokenType::Id,
+        text: name,
+        ..
+    }), group @ Lexeme::Group(_)] = &*lexed
+    else {
+        return None;
+    };
+    (name == "table" && !env.variables.contains_key(name)).then(|| table_rows(group, env))
+}
+
+/// The rows of the table whose argument list is `group`
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn table_rows(group: &Lexeme, env: &Environment) -> Result<Vec<TableRow>> {
+    let (pos, args) = split_args(&mut iter::once(group).peekable())?;
+    let (var, bounds, body) = match &*args {
+        [var, bounds @ .., body] if matches!(bounds.len(), 2 | 3) => {
+            (bound_var(var)?, bounds, body)
+        }
+        // Either 4 or 5 arguments will do, so the nearer count is the one expected
+        _ => Err(Error::ArgumentCount {
+            expected: if args.len() < 4 { 4 } else { 5 },
+            found: args.len(),
+            pos,
+        })?,
+    };
+    let mut warnings = Vec::new();
+    let bounds = bounds
+        .iter()
+        .map(|x| eval_lexemes(x, env, &mut warnings))
+        .collect::<Result<Vec<_>>>()?;
+    let (from, to, step) = (bounds[0], bounds[1], bounds.get(2).copied().unwrap_or(1.0));
+    if !(step > 0.0 && from.is_finite() && to.is_finite()) {
+        Err(Error::DomainError)?;
+    }
+    let count = ((to - from) / step).floor() + 1.0;
+    if count > f128::from(env.summation_limit) {
+        Err(Error::TooManyTerms)?;
+    }
+    let body = parse_bp(&mut body.iter().peekable(), 0, env)?;
+    let at = body.compile(env, &[var]);
+    // Stepping by index rather than by repeated addition keeps rounding from accumulating
+    Ok((0..count.max(0.0) as u32)
+        .map(|i| {
+            let x = f128::from(i).mul_add(step, from);
+            (x, at(&[x]))
+        })
+        .collect())
+}
+
+/// Evaluate `text` if it writes a whole number in another base, like `base(255, 16)`, which is
+/// `ff`, or `base(10, 2)`, which is `1010`.
+///
+/// The base runs from 2 to 36, with digits past 9 written as the letters `a` to `z`. Returns `None`
+/// if `text` is not a conversion. A number that is not whole, or a base out of range, is an
+/// [`Error::DomainError`].
+#[must_use]
+pub fn base(text: &str, env: &Environment) -> Option<Result<String>> {
+    let lexed = lex_text(text).ok()?;
+    let [Lexeme::Token(Token {
+        ty: TokenType::Id,
+        text: name,
+        ..
+    }), group @ Lexeme::Group(_)] = &*lexed
+    else {
+        return None;
+    };
+    (name == "base" && !env.variables.contains_key(name)).then(|| base_digits(group, env))
+}
+
+/// The digits of the conversion whose argument list is `group`
+#[allow(
+    clippy::float_cmp,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+fn base_digits(group: &Lexeme, env: &Environment) -> Result<String> {
+    let (pos, args) = split_args(&mut iter::once(group).peekable())?;
+    let (x, radix) = match &*args {
+        [x, radix] => (x, radix),
+        _ => Err(Error::ArgumentCount {
+            expected: 2,
+            found: args.len(),
+            pos,
+        })?,
+    };
+    let mut warnings = Vec::new();
+    let x = exact_int(eval_lexemes(x, env, &mut warnings)?)?;
+    let radix = eval_lexemes(radix, env, &mut warnings)?;
+    if radix.fract() != 0.0 || !(2.0..=36.0).contains(&radix) {
+        Err(Error::DomainError)?;
+    }
+    let radix = radix as u32;
+    let mut rest = x.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        let digit = (rest % u128::from(radix)) as u32;
+        digits.extend(char::from_digit(digit, radix));
+        rest /= u128::from(radix);
+        if rest == 0 {
+            break;
+        }
+    }
+    if x < 0 {
+        digits.push('-');
+    }
+    Ok(digits.iter().rev().collect())
+}
+
+/// Format a result for display.
+///
+/// Whole numbers have no trailing `.0`, and other numbers have as many decimals as needed unless
+/// [`Environment::precision`] fixes how many. Numbers outside the magnitudes set by
+/// [`Environment::scientific_above`] and [`Environment::scientific_below`] are written in
+/// scientific notation, like `1.5e20`, with any fixed precision applying to the mantissa.
+#[must_use]
+pub fn format_number(x: f64, env: &Environment) -> String {
+    // Adding zero turns `-0` into `0`
+    let x = x + 0.0;
+    // Compared with powers of ten rather than by the logarithm, which rounds up to the next power
+    // just below one
+    let scientific = x.is_finite()
+        && x != 0.0
+        && (x.abs() >= 10.0_f64.powi(env.scientific_above)
+            || x.abs() < 10.0_f64.powi(env.scientific_below));
+    match (scientific, env.precision) {
+        (true, Some(precision)) => format!("{x:.precision$e}"),
+        (true, None) => format!("{x:e}"),
+        (false, Some(precision)) => format!("{x:.precision$}"),
+        (false, None) => x.to_string(),
+    }
+}
+
+/// Significant digits of a [`Value`] shown with [`Environment::extended_digits`], one fewer than
+/// the 34 it holds, so that the rounding in scaling it cannot show in the last one
+pub const EXTENDED_DIGITS: usize = 33;
+
+/// Format a result for display like [`format_number`], but with [`Environment::extended_digits`],
+/// from all of `x` rather than `x` rounded to an `f64`.
+///
+/// Extended results are rounded to [`EXTENDED_DIGITS`] significant digits, then written without
+/// trailing zeros, so `0.1 + 0.2` is still `0.3` while `1/3` has 33 threes. A fixed
+/// [`Environment::precision`] and numbers without digits, like `inf`, are formatted as `f64`.
+#[must_use]
+pub fn format_value(x: Value, env: &Environment) -> String {
+    let Some((digits, exponent)) =
+        extended_digits(x).filter(|_| env.extended_digits && env.precision.is_none())
+    else {
+        return format_number(x as f64, env);
+    };
+    let digits = digits.trim_end_matches('0');
+    let sign = if x < 0.0 { "-" } else { "" };
+    let whole = exponent.unsigned_abs() as usize + 1;
+    if !(env.scientific_below..env.scientific_above).contains(&exponent) {
+        match digits.split_at(1) {
+            (first, "") => format!("{sign}{first}e{exponent}"),
+            (first, rest) => format!("{sign}{first}.{rest}e{exponent}"),
+        }
+    } else if exponent < 0 {
+        format!("{sign}0.{}{digits}", "0".repeat(whole - 2))
+    } else if digits.len() <= whole {
+        format!("{sign}{digits}{}", "0".repeat(whole - digits.len()))
+    } else {
+        format!("{sign}{}.{}", &digits[..whole], &digits[whole..])
+    }
+}
+
+/// The first [`EXTENDED_DIGITS`] significant digits of `x`, rounded, and the power of ten of the
+/// first, or `None` for zero and numbers that are not finite or too small to scale
+#[allow(
+    clippy::float_cmp,
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss
+)]
+fn extended_digits(x: Value) -> Option<(String, i32)> {
+    if x == 0.0 || !x.is_finite() {
+        return None;
+    }
+    let x = x.abs();
+    // `x` scaled to a whole number of `EXTENDED_DIGITS` digits if `exponent` is right, which a
+    // `u128` holds exactly
+    let scaled = |exponent: i32| {
+        let shift = EXTENDED_DIGITS as i32 - 1 - exponent;
+        let scale = 10.0_f128.powi(shift.abs());
+        scale
+            .is_finite()
+            .then(|| if shift < 0 { x / scale } else { x * scale }.round())
+    };
+    let limit = 10.0_f128.powi(EXTENDED_DIGITS as i32);
+    let mut exponent = x.log10().floor() as i32;
+    let mut digits = scaled(exponent)?;
+    // The logarithm can be off by one near powers of ten, and rounding can carry into a new digit
+    if digits < limit / 10.0 {
+        exponent -= 1;
+        digits = scaled(exponent)?;
+    }
+    if digits >= limit {
+        exponent += 1;
+        digits = scaled(exponent)?;
+    }
+    Some(((digits as u128).to_string(), exponent))
+}
+
+/// Evaluate the input expression, storing the result in any variables it is assigned to.
+///
+/// Assignments chain right to left, so `a = b = 5` sets both `a` and `b` to 5. The compound forms
+/// `+=`, `-=`, `*=`, and `/=` update a variable that must already be defined. Nothing is assigned
+/// unless the whole statement succeeds.
+///
+/// # Errors
+/// Returns an error upon receiving either an invalid expression or encountering an unknown
+/// operator, including a compound assignment to an undefined variable
+pub fn execute(text: &str, env: &mut Environment) -> Result<Value> {
+    execute_with_warnings(text, env).map(|(x, _)| x)
+}
+
+/// Like [`execute`], but also returns any numerical pitfalls met along the way, which do not stop
+/// evaluation
+///
+/// # Errors
+/// Returns an error upon receiving either an invalid expression or encountering an unknown
+/// operator, including a compound assignment to an undefined variable
+pub fn execute_with_warnings(text: &str, env: &mut Environment) -> Result<(Value, Vec<Warning>)> {
+    let mut warnings = Vec::new();
+    let lexed = lex_statement(text, env, &mut warnings)?;
+    let (targets, rest) = split_assignments(&lexed);
+    let mut value = eval_statement(rest, env, &mut warnings)?;
+    let mut assignments = Vec::new();
+    for (name, op) in targets.into_iter().rev() {
+        if op != "=" {
+            let old = *env
+                .variables
+                .get(name)
+                .ok_or_else(|| Error::UnknownIdentifier(name.clone()))?;
+            let op = match &**op {
+                "+=" => BinOp::Add,
+                "-=" => BinOp::Sub,
+                "*=" => BinOp::Mul,
+                _ => BinOp::Div,
+            };
+            value = Expression::BinOp {
+                lhs: Box::new(Expression::Num(old)),
+                op,
+                rhs: Box::new(Expression::Num(value)),
+            }
+            .eval(env, &mut Vec::new(), &mut warnings)?;
+        }
+        assignments.push((name.clone(), value));
+    }
+    env.variables.extend(assignments);
+    Ok(check_result(value, warnings, env))
+}
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+
+    /// `text` evaluated in the default environment
+    fn eval(text: &str) -> Result<Value> {
+        eval_with(text, &Environment::default())
+    }
+
+    #[test]
+    fn integer_powers_are_exact() {
+        assert_eq!(eval("pow(2, 10)"), Ok(1024.0));
+        assert_eq!(eval("pow(10, 2)"), Ok(100.0));
+        assert_eq!(eval("10^2"), Ok(100.0));
+        assert_eq!(eval("pow(3, 40)"), Ok(12_157_665_459_056_928_801.0));
+        assert_eq!(eval("3^40"), eval("pow(3, 40)"));
+        assert_eq!(eval("pow(10, -2)"), Ok(1.0 / 100.0));
+        assert_eq!(eval("pow(-2, 3)"), Ok(-8.0));
+    }
+
+    #[test]
+    fn invisible_characters_are_skipped() {
+        assert_eq!(eval("\u{feff}2 + 3"), Ok(5.0));
+        assert_eq!(eval("2\u{200b}0 * 2"), Ok(40.0));
+        assert_eq!(eval("\u{feff}1\u{200b} +\u{2060} 1"), Ok(2.0));
+    }
+
+    #[test]
+    fn caret_in_programmer_mode() {
+        let programmer = Environment {
+            programmer_mode: true,
+            ..Environment::default()
+        };
+        assert_eq!(eval("5 ^ 3"), Ok(125.0));
+        assert_eq!(eval_with("5 ^ 3", &programmer), Ok(6.0));
+        assert_eq!(eval("5 ** 3"), Ok(125.0));
+        assert_eq!(eval_with("5 ** 3", &programmer), Ok(125.0));
+        assert_eq!(eval_with("1 + 2 ^ 3", &programmer), Ok(0.0));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let ieee = Environment {
+            ieee_division: true,
+            ..Environment::default()
+        };
+        assert_eq!(eval("1/0"), Err(Error::DivByZero));
+        assert_eq!(eval("0/0"), Err(Error::DivByZero));
+        assert!(eval("1/0.0000001").is_ok_and(|x| (x - 10_000_000.0).abs() < 1e-20));
+        assert_eq!(eval("0^-1"), Err(Error::DivByZero));
+        assert_eq!(eval("pow(0, -2)"), Err(Error::DivByZero));
+        assert_eq!(eval("0^0"), Ok(1.0));
+        assert_eq!(eval_with("1/0", &ieee), Ok(f128::INFINITY));
+        assert!(eval_with("0/0", &ieee).is_ok_and(f128::is_nan));
+        assert_eq!(eval_with("0^-1", &ieee), Ok(f128::INFINITY));
+    }
+
+    #[test]
+    fn chained_and_compound_assignment() {
+        let mut env = Environment::default();
+        assert_eq!(execute("a = b = 5", &mut env), Ok(5.0));
+        assert_eq!(env.variables["a"], 5.0);
+        assert_eq!(env.variables["b"], 5.0);
+        assert_eq!(execute("x = 10", &mut env), Ok(10.0));
+        assert_eq!(execute("x += 3", &mut env), Ok(13.0));
+        assert_eq!(execute("x -= 1", &mut env), Ok(12.0));
+        assert_eq!(execute("x *= 2", &mut env), Ok(24.0));
+        assert_eq!(execute("x /= 8", &mut env), Ok(3.0));
+        assert_eq!(execute("y = x += 1", &mut env), Ok(4.0));
+        assert_eq!((env.variables["x"], env.variables["y"]), (4.0, 4.0));
+        assert_eq!(
+            execute("z += 1", &mut env),
+            Err(Error::UnknownIdentifier("z".to_owned()))
+        );
+        assert!(!env.variables.contains_key("z"));
+        assert_eq!(execute("x /= 0", &mut env), Err(Error::DivByZero));
+        assert_eq!(env.variables["x"], 4.0);
+    }
+
+    #[test]
+    fn precedence_hints() {
+        let env = Environment::default();
+        let hint = |text| precedence_hint(text, &env);
+        assert_eq!(hint("1/2 x").as_deref(), Some("(1 / (2 * x))"));
+        assert_eq!(hint("1/2(x)").as_deref(), Some("(1 / (2 * x))"));
+        assert_eq!(hint("2^3 x").as_deref(), Some("(2 ^ (3 * x))"));
+        assert_eq!(hint("(1/2) x"), None);
+        assert_eq!(hint("1/(2 x)"), None);
+        assert_eq!(hint("2 x^2"), None);
+    }
+
+    #[test]
+    fn sum_and_product_symbols() {
+        assert_eq!(eval("∑(k, 1, 4, k)"), Ok(10.0));
+        assert_eq!(eval("∏(k, 1, 4, k)"), Ok(24.0));
+        assert_eq!(eval("2 * ∑(k, 1, 3, k^2) + ∏(k, 1, 3, 2)"), Ok(36.0));
+        assert_eq!(eval("∑(k, 1, 4, k)"), eval("sum(k, 1, 4, k)"));
+    }
+
+    /// Whether `text` evaluates to within `1e-30` of `expected`
+    fn near(text: &str, expected: Value) -> bool {
+        eval(text).is_ok_and(|x| (x - expected).abs() < 1e-30)
+    }
+
+    #[test]
+    fn negative_and_fractional_exponents() {
+        assert_eq!(eval("2^-1"), Ok(0.5));
+        assert_eq!(eval("4^0.5"), Ok(2.0));
+        assert!(near("8^(1/3)", 2.0));
+        assert!(near("(-8)^(1/3)", -2.0));
+        assert!(near("(-8)^(2/3)", 4.0));
+        assert_eq!(eval("-8^(1/3)"), eval("-(8^(1/3))"));
+        assert_eq!(eval("(-8)^0.5"), Err(Error::DomainError));
+    }
+
+    #[test]
+    fn number_formats() {
+        let env = Environment::default();
+        let fixed = Environment {
+            precision: Some(2),
+            ..Environment::default()
+        };
+        let narrow = Environment {
+            scientific_above: 3,
+            scientific_below: -2,
+            ..Environment::default()
+        };
+        for (x, default, two_places, narrow_range) in [
+            (5.0, "5", "5.00", "5"),
+            (2.5, "2.5", "2.50", "2.5"),
+            (-0.0, "0", "0.00", "0"),
+            (100_000_000.0, "100000000", "100000000.00", "1e8"),
+            (0.000_000_1, "1e-7", "1.00e-7", "1e-7"),
+            (0.000_001, "0.000001", "0.00", "1e-6"),
+            (1e15, "1e15", "1.00e15", "1e15"),
+            (
+                999_999_999_999_999.0,
+                "999999999999999",
+                "999999999999999.00",
+                "9.99999999999999e14",
+            ),
+            (-1234.5678, "-1234.5678", "-1234.57", "-1.2345678e3"),
+            (0.01, "0.01", "0.01", "0.01"),
+            (0.009, "0.009", "0.01", "9e-3"),
+            (
+                1.0 / 3.0,
+                "0.3333333333333333",
+                "0.33",
+                "0.3333333333333333",
+            ),
+            (f64::INFINITY, "inf", "inf", "inf"),
+            (f64::NAN, "NaN", "NaN", "NaN"),
+        ] {
+            assert_eq!(format_number(x, &env), default);
+            assert_eq!(format_number(x, &fixed), two_places);
+            assert_eq!(format_number(x, &narrow), narrow_range);
+        }
+    }
+
+    #[test]
+    fn greek_variables() {
+        let mut env = Environment::default();
+        assert!(execute("θ = pi/4", &mut env).is_ok());
+        assert!(execute("sin(θ)^2", &mut env).is_ok_and(|x| (x - 0.5).abs() < 1e-30));
+        assert_eq!(execute("αβ = 2", &mut env), Ok(2.0));
+        assert_eq!(execute("αβ * 3 + αβ", &mut env), Ok(8.0));
+        assert_eq!(
+            execute("α", &mut env),
+            Err(Error::UnknownIdentifier("α".to_owned()))
+        );
+    }
+
+    #[test]
+    fn trailing_stars() {
+        assert_eq!(eval("3*"), Err(Error::UnexpectedEnd));
+        assert_eq!(eval("3**"), Err(Error::UnexpectedEnd));
+        let unexpected = |pos| Error::UnexpectedToken {
+            found: "*".to_owned(),
+            pos,
+        };
+        assert_eq!(eval("3***4"), Err(unexpected(3)));
+        assert_eq!(eval("3 * * 4"), Err(unexpected(4)));
+        assert_eq!(eval("3**4"), Ok(81.0));
+    }
+
+    /// The next of a sequence of pseudo-random numbers from `state`, by xorshift
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// One of `options`, picked with [`next_random`]
+    #[allow(clippy::cast_possible_truncation)]
+    fn pick<'a>(state: &mut u64, options: &[&'a str]) -> &'a str {
+        options[next_random(state) as usize % options.len()]
+    }
+
+    /// Whether two results are the same, counting NaNs with the same bits as equal
+    fn same(a: &Result<Value>, b: &Result<Value>) -> bool {
+        match (a, b) {
+            (Ok(a), Ok(b)) => a.to_bits() == b.to_bits(),
+            (a, b) => a == b,
+        }
+    }
+
+    #[test]
+    fn arithmetic_fast_path_matches_parser() {
+        const NUMBERS: [&str; 12] = [
+            "0", "1", "2", "3.5", "0.1", "1e3", "10", "7", "0.0", "1e308", "2.5e-3", "01",
+        ];
+        const OPERATORS: [&str; 10] = ["+", "-", "*", "/", "^", "**", "·", "↑", " . ", " + "];
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for programmer_mode in [false, true] {
+            let env = Environment {
+                programmer_mode,
+                ..Environment::default()
+            };
+            for _ in 0..5000 {
+                let mut text = String::new();
+                for i in 0..=next_random(&mut state) % 8 {
+                    if i > 0 {
+                        text.push_str(pick(&mut state, &OPERATORS));
+                    }
+                    text.push_str(pick(&mut state, &NUMBERS));
+                }
+                let lexed = lex_text(&text).unwrap();
+                let (mut fast_warnings, mut tree_warnings) = (Vec::new(), Vec::new());
+                let fast = eval_arithmetic(&lexed, &env, &mut fast_warnings)
+                    .unwrap_or_else(|| panic!("{text} is not plain arithmetic"));
+                let tree = parse_bp(&mut lexed.iter().peekable(), 0, &env)
+                    .and_then(|x| x.eval(&env, &mut Vec::new(), &mut tree_warnings));
+                assert!(same(&fast, &tree), "{text}: {fast:?} != {tree:?}");
+                assert_eq!(fast_warnings, tree_warnings, "{text}");
+            }
+        }
+    }
+
+    /// An expression generated for [`parser_matches_reference`], with the meaning the grammar
+    /// gives it
+    enum Reference {
+        Num(&'static str),
+        Neg(Box<Self>),
+        /// A binary operator, one of `+`, `-`, `*`, `/`, and `^`
+        Op(char, Box<Self>, Box<Self>),
+        /// Juxtaposition with a parenthesized argument, as in `2 (3)`
+        Juxtaposed(Box<Self>, Box<Self>),
+    }
+
+    impl Reference {
+        /// A random expression from `state`, nested at most `depth` deep. Exponents are small
+        /// whole numbers, so powers stay exact and never need a root of a negative number.
+        fn random(state: &mut u64, depth: u32) -> Self {
+            if depth == 0 {
+                return Self::Num(pick(state, &["0", "1", "2", "3", "7", "10", "0.5", "2.5"]));
+            }
+            match next_random(state) % 8 {
+                0 => Self::Num(pick(state, &["0", "1", "2", "3", "7", "10", "0.5", "2.5"])),
+                1 => Self::Neg(Box::new(Self::random(state, depth - 1))),
+                2 => Self::Juxtaposed(
+                    Box::new(Self::random(state, depth - 1)),
+                    Box::new(Self::random(state, depth - 1)),
+                ),
+                3 => {
+                    let base = Box::new(Self::random(state, depth - 1));
+                    let exponent = match pick(state, &["0", "1", "2", "3", "-1", "-2"]) {
+                        x if x.starts_with('-') => Self::Neg(Box::new(Self::Num(&x[1..]))),
+                        x => Self::Num(x),
+                    };
+                    Self::Op('^', base, Box::new(exponent))
+                }
+                x => {
+                    let op = ['+', '-', '*', '/'][usize::try_from(x).unwrap() - 4];
+                    let lhs = Box::new(Self::random(state, depth - 1));
+                    Self::Op(op, lhs, Box::new(Self::random(state, depth - 1)))
+                }
+            }
+        }
+
+        /// How tightly the expression binds, to tell where it needs parentheses
+        fn precedence(&self) -> u8 {
+            match self {
+                Self::Op('+' | '-', ..) => 1,
+                Self::Op('*' | '/', ..) => 2,
+                Self::Neg(_) => 3,
+                Self::Op(..) => 4,
+                Self::Juxtaposed(..) => 5,
+                Self::Num(_) => 6,
+            }
+        }
+
+        /// The expression written out with parentheses only where the grammar needs them to
+        /// bind at least as tightly as `min`, and at random elsewhere, and with the operators
+        /// spelled in any of their ways
+        fn write(&self, min: u8, state: &mut u64) -> String {
+            let text = match self {
+                Self::Num(x) => (*x).to_owned(),
+                Self::Neg(x) => format!("-{}", x.write(4, state)),
+                Self::Juxtaposed(lhs, rhs) => {
+                    format!("{} ({})", lhs.write(5, state), rhs.write(0, state))
+                }
+                Self::Op(op, lhs, rhs) => {
+                    let (left, right) = match op {
+                        '+' | '-' => (1, 2),
+                        '*' | '/' => (2, 3),
+                        _ => (5, 3),
+                    };
+                    let op = match op {
+                        '*' => pick(state, &["*", "·"]),
+                        '^' => pick(state, &["^", "**"]),
+                        '+' => "+",
+                        '-' => "-",
+                        _ => "/",
+                    };
+                    format!(
+                        "{} {op} {}",
+                        lhs.write(left, state),
+                        rhs.write(right, state)
+                    )
+                }
+            };
+            if self.precedence() < min || next_random(state).is_multiple_of(10) {
+                format!("({text})")
+            } else {
+                text
+            }
+        }
+
+        /// The value of the expression, evaluated directly by the meaning of its operators
+        #[allow(clippy::cast_possible_truncation)]
+        fn eval(&self) -> Result<Value> {
+            Ok(match self {
+                Self::Num(x) => Value::from(x.parse::<f64>().unwrap()),
+                Self::Neg(x) => -x.eval()?,
+                Self::Juxtaposed(lhs, rhs) => lhs.eval()? * rhs.eval()?,
+                Self::Op(op, lhs, rhs) => {
+                    let (lhs, rhs) = (lhs.eval()?, rhs.eval()?);
+                    match op {
+                        '+' => lhs + rhs,
+                        '-' => lhs - rhs,
+                        '*' => lhs * rhs,
+                        '/' if rhs == 0.0 => Err(Error::DivByZero)?,
+                        '/' => lhs / rhs,
+                        _ if lhs == 0.0 && rhs < 0.0 => Err(Error::DivByZero)?,
+                        _ => lhs.powi(rhs as i32),
+                    }
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn parser_matches_reference() {
+        let mut state = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..20_000 {
+            let expression = Reference::random(&mut state, 5);
+            let text = expression.write(0, &mut state);
+            let (result, expected) = (eval(&text), expression.eval());
+            assert!(
+                same(&result, &expected),
+                "{text}: {result:?} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn document_lines() {
+        let document = "Budget\n\nrent = 1200\nfood = 300\nsee the notes\nrent + food\n1/0\n2 +";
+        let results = evaluate_document(document, &mut Environment::default());
+        assert_eq!(
+            results,
+            [
+                None,
+                None,
+                Some(Ok(Value::from(1200))),
+                Some(Ok(Value::from(300))),
+                None,
+                Some(Ok(Value::from(1500))),
+                Some(Err(Error::DivByZero)),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn scientific_constants() {
+        assert!(near("Na * kB", 8.314_462_618_153_24));
+        assert!(near("Na * kB - R", 0.0));
+        assert!(near("h", 6.626_070_15e-34));
+        let mut env = Environment::default();
+        assert_eq!(execute("Na = 2", &mut env), Ok(2.0));
+        assert_eq!(eval_with("Na * 3", &env), Ok(6.0));
+    }
+
+    #[test]
+    fn lenient_trailing_text() {
+        let lenient = Environment {
+            lenient_trailing: true,
+            ..Environment::default()
+        };
+        let unknown = |name: &str| Err(Error::UnknownIdentifier(name.to_owned()));
+        assert_eq!(eval("2 + 3 seconds"), unknown("seconds"));
+        assert_eq!(
+            eval_with_warnings("2 + 3 seconds", &lenient),
+            Ok((5.0, vec![Warning::IgnoredTrailing]))
+        );
+        assert_eq!(
+            eval_with_warnings("2 + 3 # total", &lenient),
+            Ok((5.0, vec![Warning::IgnoredTrailing]))
+        );
+        for text in ["2 * y", "3 + y", "2 * y seconds"] {
+            assert_eq!(eval(text), unknown("y"));
+            assert_eq!(eval_with(text, &lenient), unknown("y"));
+        }
+        assert!(eval_with("3 + #", &lenient).is_err());
+    }
+
+    #[test]
+    fn plus_minus() {
+        let mut env = Environment::default();
+        for assignment in ["a = 1", "b = -3", "c = 2"] {
+            execute(assignment, &mut env).unwrap();
+        }
+        let roots = "(-b ± sqrt(b^2 - 4*a*c)) / (2*a)";
+        assert_eq!(eval_plus_minus(roots, &env), Some(Ok((2.0, 1.0))));
+        assert_eq!(eval_plus_minus("1 ± 2 ± 3", &env), Some(Ok((6.0, -4.0))));
+        assert_eq!(eval_plus_minus("±1", &env), Some(Ok((1.0, -1.0))));
+        assert_eq!(eval_plus_minus("1 + 2", &env), None);
+        assert!(eval_plus_minus("1/0 ± 1", &env).is_some_and(|x| x.is_err()));
+    }
+
+    #[test]
+    fn timeout() {
+        let expired = Environment {
+            deadline: Some(Instant::now()),
+            ..Environment::default()
+        };
+        for text in [
+            "sum(k, 1, 100000, k^2)",
+            "sum(k, 1, 200, sum(j, 1, 200, sum(i, 1, 200, i)))",
+            "integ(x, 0, 1, sum(k, 1, 200, sin(k x)))",
+        ] {
+            assert_eq!(eval_with(text, &expired), Err(Error::Timeout));
+            let start = Instant::now();
+            let result = evaluate_with_timeout(text, Duration::from_millis(1));
+            assert!(start.elapsed() < Duration::from_millis(100), "{text}");
+            assert!(result.is_ok() || result == Err(Error::Timeout), "{text}");
+        }
+        assert_eq!(
+            evaluate_with_timeout("sum(k, 1, 4, k)", Duration::from_secs(1)),
+            Ok(10.0)
+        );
+        let nested = parse_str("sum(j, 1, 200, sum(i, 1, 200, i x))", &expired).unwrap();
+        assert_eq!(
+            nested.compile(&expired, &["x"])(&[1.0]),
+            Err(Error::Timeout)
+        );
+    }
+
+    #[test]
+    fn free_variables_in_statements() {
+        let mut env = Environment::default();
+        let free = |text, env: &Environment| free_variables(text, env).unwrap();
+        assert_eq!(free("sin(x) + a*y", &env), ["x", "a", "y"]);
+        assert_eq!(
+            free("sqrt(abs(sin(x y)) + pow(z, pi))", &env),
+            ["x", "y", "z"]
+        );
+        assert_eq!(
+            free("sum(k, 1, n, k^2 + sum(j, 1, k, j m))", &env),
+            ["n", "m"]
+        );
+        assert_eq!(free("sum(k, 1, 3, k) + k", &env), ["k"]);
+        assert_eq!(free("x = y + 1", &env), ["y"]);
+        assert_eq!(free("a = b = d * 2", &env), ["d"]);
+        assert_eq!(free("x = x + 1", &env), ["x"]);
+        assert_eq!(free("total += price", &env), ["total", "price"]);
+        execute("y = 2", &mut env).unwrap();
+        execute("total = 0", &mut env).unwrap();
+        assert_eq!(free("x = y + 1", &env), Vec::<String>::new());
+        assert_eq!(free("total += price * y", &env), ["price"]);
+        assert_eq!(free("2 y + e + c", &env), Vec::<String>::new());
+        assert!(free_variables("x = ", &env).is_err());
+    }
+
+    #[test]
+    fn variables_resolve_when_evaluated() {
+        let mut env = Environment::default();
+        let expression = parse_str("2 r + pi", &env).unwrap();
+        let unknown = |name: &str| Err(Error::UnknownIdentifier(name.to_owned()));
+        assert_eq!(
+            expression.eval(&env, &mut Vec::new(), &mut Vec::new()),
+            unknown("r")
+        );
+        execute("r = 3", &mut env).unwrap();
+        assert!(expression
+            .eval(&env, &mut Vec::new(), &mut Vec::new())
+            .is_ok_and(|x| (x - (6.0 + core::f128::consts::PI)).abs() < 1e-30));
+        assert_eq!(eval("undefined + 1"), unknown("undefined"));
+        assert_eq!(execute("pi = 3", &mut env), Ok(3.0));
+        assert_eq!(eval_with("2 pi", &env), Ok(6.0));
+        assert_eq!(eval_with("sum(r, 1, 4, r) + r", &env), Ok(13.0));
+        assert_eq!(eval_with("sum(k, 1, 2, k) + k", &env), unknown("k"));
+    }
+
+    #[test]
+    fn multiplication_dot() {
+        assert_eq!(eval("3.5"), Ok(3.5));
+        assert_eq!(eval(".5"), Ok(0.5));
+        assert_eq!(eval("3 . 5"), Ok(15.0));
+        assert_eq!(eval("3.5 . 2"), Ok(7.0));
+        assert_eq!(eval("3.5 · 2"), Ok(7.0));
+        assert_eq!(eval("(1 + 2) . 4"), Ok(12.0));
+        assert!(eval("3.5.2").is_err());
+    }
+
+    #[test]
+    fn roots_and_minima() {
+        let close = |text, expected: Value, tolerance: Value| {
+            eval_with_warnings(text, &Environment::default())
+                .is_ok_and(|(x, warnings)| (x - expected).abs() < tolerance && warnings.is_empty())
+        };
+        let sqrt2 = core::f128::consts::SQRT_2;
+        assert!(close("root(x^2 - 2, x, 0, 2)", sqrt2, 1e-30));
+        assert!(close("root(x^2 - 2, x, -2, 0)", -sqrt2, 1e-30));
+        assert!(close("root(sqrt(x) - 3, x, 0, 20)", 9.0, 1e-30));
+        assert!(close("root(x^3 - 27, x, 0, 10)", 3.0, 1e-30));
+        assert_eq!(eval("root(x - 1, x, 1, 2)"), Ok(1.0));
+        assert_eq!(eval("root(x^2 + 1, x, -1, 1)"), Err(Error::DomainError));
+        assert!(close("minimize(x^2 - 4, x, -5, 5)", 0.0, 1e-15));
+        assert!(close("minimize((x - 3)^2 + 1, x, -10, 10)", 3.0, 1e-15));
+        assert!(close("minimize(x^2, x, 2, 5)", 2.0, 1e-15));
+        assert_eq!(eval("minimize(x, x, 1, 1)"), Err(Error::DomainError));
+    }
+
+    #[test]
+    fn derivatives() {
+        let close = |text, expected: Value| eval(text).is_ok_and(|x| (x - expected).abs() < 1e-18);
+        assert!(close("deriv(x, 3, x^2)", 6.0));
+        assert!(close("deriv(t, 2, t^3 - 4 t)", 8.0));
+        assert!(close("deriv(x, 0, sin(x))", 1.0));
+        assert!(close("deriv(x, 1, ln(x))", 1.0));
+        assert!(close("deriv(x, 1000000, x^2)", 2_000_000.0));
+        assert!(close("deriv(x, 5, 7)", 0.0));
+        assert_eq!(eval("deriv(x, 1, 1/(x - x))"), Err(Error::DivByZero));
+    }
+
+    #[test]
+    fn summation_limit() {
+        let env = Environment {
+            summation_limit: 10,
+            ..Environment::default()
+        };
+        assert_eq!(eval_with("sum(k, 1, 10, k)", &env), Ok(55.0));
+        assert_eq!(eval_with("prod(k, 1, 10, 1)", &env), Ok(1.0));
+        assert_eq!(
+            eval_with("sum(k, 1, 11, k)", &env),
+            Err(Error::TooManyTerms)
+        );
+        assert_eq!(
+            eval_with("prod(k, 0, 10, 2)", &env),
+            Err(Error::TooManyTerms)
+        );
+        // Only whole values of the variable count towards the limit
+        assert_eq!(eval_with("sum(k, 0.5, 10.5, k)", &env), Ok(55.0));
+        let limit = DEFAULT_SUMMATION_LIMIT;
+        assert!(eval(&format!("sum(k, 1, {limit}, 1)")).is_ok());
+        assert_eq!(
+            eval(&format!("sum(k, 0, {limit}, 1)")),
+            Err(Error::TooManyTerms)
+        );
+        let coarse = Environment {
+            integration_steps: 2,
+            ..Environment::default()
+        };
+        // Two steps of Simpson's rule are exact for a cubic, but not for a quartic
+        assert_eq!(eval_with("integ(x, 0, 2, x^3)", &coarse), Ok(4.0));
+        assert!(eval_with("integ(x, 0, 2, x^4)", &coarse).is_ok_and(|x| x > 6.4 + 0.1));
+    }
+
+    #[test]
+    fn strict_identifiers() {
+        let mut strict = Environment {
+            strict_identifiers: true,
+            ..Environment::default()
+        };
+        let unknown = Err(Error::UnknownIdentifier("sln".to_owned()));
+        assert_eq!(eval_with_warnings("sln(2)", &strict), unknown);
+        assert!(eval_with_warnings("sin(2)", &strict).is_ok_and(|x| x.1.is_empty()));
+        execute("sln = 3", &mut strict).unwrap();
+        assert_eq!(
+            eval_with_warnings("sln(2)", &strict),
+            Ok((6.0, vec![Warning::MultipliedName]))
+        );
+        assert_eq!(eval_with_warnings("sln (2)", &strict), Ok((6.0, vec![])));
+        assert_eq!(
+            eval_with_warnings("2 + pi(2)", &strict).map(|x| x.1),
+            Ok(vec![Warning::MultipliedName])
+        );
+        strict.strict_identifiers = false;
+        assert_eq!(eval_with_warnings("sln(2)", &strict), Ok((6.0, vec![])));
+    }
+
+    #[test]
+    fn up_arrow_powers() {
+        assert_eq!(eval("2↑3↑2"), Ok(512.0));
+        assert_eq!(eval("2↑3↑2"), eval("2^(3^2)"));
+        assert_eq!(eval("(2↑3)↑2"), Ok(64.0));
+        assert_eq!(eval("2 ↑ 3 * 2"), Ok(16.0));
+        assert_eq!(eval("-2↑2"), Ok(-4.0));
+    }
+
+    #[test]
+    fn whole_number_functions() {
+        assert_eq!(eval("gcd(1000000000000, 8)"), Ok(8.0));
+        assert_eq!(eval("gcd(-12, 18)"), Ok(6.0));
+        assert_eq!(eval("gcd(0, 0)"), Ok(0.0));
+        assert_eq!(
+            eval("lcm(9007199254740991, 9007199254740992)"),
+            Ok(81_129_638_414_606_672_688_589_750_403_072.0)
+        );
+        assert_eq!(eval("lcm(2^53, 3)"), Ok(27_021_597_764_222_976.0));
+        assert_eq!(eval("lcm(2^60 + 1, 2^60 - 1)"), Err(Error::DomainError));
+        assert_eq!(eval("lcm(4, 0)"), Ok(0.0));
+        assert_eq!(eval("gcd(2.5, 5)"), Err(Error::DomainError));
+        assert_eq!(eval("gcd(2^120, 4)"), Err(Error::DomainError));
+        assert_eq!(eval("mod(-7, 3)"), Ok(2.0));
+        assert_eq!(eval("mod(7, -3)"), Ok(-2.0));
+        assert_eq!(eval("mod(2^53 + 1, 2)"), Ok(1.0));
+        assert_eq!(eval("mod(5, 0)"), Err(Error::DivByZero));
+    }
+
+    #[test]
+    fn leading_zeros() {
+        assert_eq!(eval("0123"), Ok(123.0));
+        assert_eq!(eval("007"), Ok(7.0));
+        assert_eq!(eval("010 + 1"), Ok(11.0));
+        assert_eq!(eval("00.5"), Ok(0.5));
+        let prefixed = |found: &str| {
+            Err(Error::RadixPrefix {
+                found: found.to_owned(),
+                pos: 0,
+            })
+        };
+        assert_eq!(eval("0o17"), prefixed("0o17"));
+        assert_eq!(eval("0x1F"), prefixed("0x1F"));
+    }
+
+    #[test]
+    fn parse_error_offsets() {
+        let env = Environment::default();
+        let pos = |text| parse_str(text, &env).err().unwrap().pos;
+        assert_eq!(pos("2+"), 2);
+        // An unclosed parenthesis is reported where it opens
+        assert_eq!(pos("(1"), 0);
+        assert_eq!(pos("sin"), 3);
+        assert_eq!(pos("2 @ 3"), 2);
+        assert_eq!(pos("2+  "), 2);
+        let error = parse_str("2 @ 3", &env).err().unwrap();
+        assert!(error.message.contains('@'));
+    }
+
+    #[test]
+    fn log_base_setting() {
+        let natural = Environment {
+            log_base: LogBase::Natural,
+            ..Environment::default()
+        };
+        let log = |text| eval_with(text, &natural).unwrap();
+        assert!(near("log(100)", 2.0));
+        assert!(near("ln(e)", 1.0));
+        assert!((log("log(e)") - 1.0).abs() < 1e-30);
+        assert!((log("log(100)") - 100f128.ln()).abs() < 1e-30);
+        assert!((log("log10(100)") - 2.0).abs() < 1e-30);
+    }
+
+    #[test]
+    fn bracket_kinds() {
+        assert_eq!(eval("[2+3]*4"), Ok(20.0));
+        assert_eq!(eval("{2+3}*4"), Ok(20.0));
+        assert_eq!(eval("{[2+3]*4}"), Ok(20.0));
+        assert_eq!(eval("{[(1+1)*2]^2} - 1"), Ok(15.0));
+        assert_eq!(eval("2[3]"), Ok(6.0));
+        assert_eq!(
+            eval("[2+3)"),
+            Err(Error::MismatchedBracket { found: ')', pos: 4 })
+        );
+        assert_eq!(
+            eval("{[2+3}]"),
+            Err(Error::MismatchedBracket { found: '}', pos: 5 })
+        );
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn significant_figures() {
+        assert!(near("sigfig(3.14159, 3)", 3.14));
+        assert_eq!(eval("sigfig(12345, 2)"), Ok(12_000.0));
+        assert_eq!(eval("sigfig(12345, 9)"), Ok(12_345.0));
+        assert_eq!(eval("sigfig(99.96, 3)"), Ok(100.0));
+        assert!(near("sigfig(0.00123456, 2)", 0.0012));
+        assert!(near("sigfig(0.5, 1)", 0.5));
+        assert!(near("sigfig(-3.14159, 3)", -3.14));
+        assert_eq!(eval("sigfig(-12345, 1)"), Ok(-10_000.0));
+        assert_eq!(eval("sigfig(0, 3)"), Ok(0.0));
+        assert_eq!(eval("sigfig(6.02 * 10^23, 1)"), Ok(6e23));
+        for bad in ["sigfig(5, 0)", "sigfig(5, -1)", "sigfig(5, 1.5)"] {
+            assert_eq!(eval(bad), Err(Error::DomainError), "{bad}");
+        }
+    }
+
+    #[test]
+    fn bases() {
+        let env = Environment::default();
+        let base = |text| base(text, &env);
+        assert_eq!(base("base(255, 16)"), Some(Ok("ff".to_owned())));
+        assert_eq!(base("base(35, 36)"), Some(Ok("z".to_owned())));
+        assert_eq!(base("base(10, 2)"), Some(Ok("1010".to_owned())));
+        assert_eq!(base("base(0, 2)"), Some(Ok("0".to_owned())));
+        assert_eq!(base("base(-255, 16)"), Some(Ok("-ff".to_owned())));
+        assert_eq!(base("base(2^8 - 1, 4 * 4)"), Some(Ok("ff".to_owned())));
+        for bad in [
+            "base(10, 1)",
+            "base(10, 37)",
+            "base(2.5, 2)",
+            "base(10, 2.5)",
+        ] {
+            assert_eq!(base(bad), Some(Err(Error::DomainError)), "{bad}");
+        }
+        assert_eq!(base("2 + 3"), None);
+        assert_eq!(base("base"), None);
+    }
+
+    #[test]
+    fn simplify_identities() {
+        let env = Environment::default();
+        let simplified = |text| parse_str(text, &env).unwrap().simplify().to_string();
+        for (text, expected) in [
+            ("x * 1", "x"),
+            ("1 * x", "x"),
+            ("x + 0", "x"),
+            ("0 + x", "x"),
+            ("x - 0", "x"),
+            ("x * 0", "0"),
+            ("0 * x", "0"),
+            ("x^1", "x"),
+            ("x^0", "1"),
+            ("(x + 0) * (y^1 * 1)", "(x * y)"),
+            ("(x * 0 + y)^(1 * 1)", "y"),
+            ("0 - x", "(0 - x)"),
+            ("x / 1", "(x / 1)"),
+            ("(1 / 0) * 0", "((1 / 0) * 0)"),
+        ] {
+            assert_eq!(simplified(text), expected, "{text}");
+        }
+        let texts = [
+            "x * 1 + y^1",
+            "(x + 0) * (y - 0)",
+            "x * 0 + y^0",
+            "(x^1 + 0) * 1 - y * 0",
+            "2 x^1 + (y + 0)^2",
+        ];
+        for (x, y) in [(3.0, -2.5), (0.0, 7.0), (-1.5, 0.0), (1e20, 1e-20)] {
+            let mut env = Environment::default();
+            env.variables.insert("x".to_owned(), x);
+            env.variables.insert("y".to_owned(), y);
+            for text in texts {
+                let expression = parse_str(text, &env).unwrap();
+                let expected = expression.eval(&env, &mut Vec::new(), &mut Vec::new());
+                let actual = expression
+                    .simplify()
+                    .eval(&env, &mut Vec::new(), &mut Vec::new());
+                assert_eq!(actual, expected, "{text} at x = {x:?}, y = {y:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn long_input_is_turned_away() {
+        let long = "1+".repeat(25_000) + "1";
+        assert_eq!(long.len(), 50_001);
+        assert_eq!(eval(&long), Err(Error::TooLong));
+        let limit = "1+".repeat(MAX_EXPRESSION_LEN / 2 - 1) + "1";
+        assert!(limit.len() < MAX_EXPRESSION_LEN);
+        assert_eq!(eval(&limit), Err(Error::TooLong));
+        assert_eq!(eval(&"1+".repeat(400)), Err(Error::UnexpectedEnd));
+        // Deep nesting is refused before it can overflow the stack
+        let nested = "(".repeat(5000) + "1" + &")".repeat(5000);
+        assert_eq!(eval(&nested), Err(Error::TooLong));
+        assert_eq!(eval(&("-".repeat(3000) + "1")), Err(Error::TooLong));
+        assert_eq!(eval(&("(".repeat(100) + "1" + &")".repeat(100))), Ok(1.0));
+    }
+
+    #[test]
+    fn extended_digits_display() {
+        let env = Environment::default();
+        let extended = Environment {
+            extended_digits: true,
+            ..Environment::default()
+        };
+        let shown = |text, env| format_value(eval_with(text, env).unwrap(), env);
+        let sum = eval("0.1 + 0.2").unwrap();
+        assert!((sum - 0.3).abs() < f128::from((0.1_f64 + 0.2 - 0.3).abs()));
+        assert_eq!(shown("0.1 + 0.2", &extended), "0.3");
+        assert_eq!(shown("0.1 + 0.2", &env), "0.3");
+        assert_eq!(shown("1/3", &extended), format!("0.{}", "3".repeat(33)));
+        assert_eq!(shown("1/3", &env), "0.3333333333333333");
+        assert_eq!(shown("2/3", &extended), format!("0.{}7", "6".repeat(32)));
+        assert_eq!(shown("2^64", &extended), "1.8446744073709551616e19");
+        let fixed = Environment {
+            precision: Some(2),
+            extended_digits: true,
+            ..Environment::default()
+        };
+        assert_eq!(shown("1/3", &fixed), "0.33");
+    }
+
+    #[test]
+    fn compiled_matches_interpreted() {
+        let mut env = Environment::default();
+        execute("k = 2.5", &mut env).unwrap();
+        let bits = |x: &Result<Value>| x.clone().map(f128::to_bits);
+        for text in [
+            "sin(x) + cos(2*x)",
+            "sum(n, 1, 5, n*x) + k - y",
+            "x*x + 3*x - k + x*x*x",
+            "1/x + y",
+            "sqrt(x) * ln(y)",
+            "integ(t, 0, x, t^2 + y)",
+            "x^y - pi",
+            "undefined + x",
+        ] {
+            let expression = parse_str(text, &env).unwrap();
+            let compiled = expression.compile(&env, &["x", "y"]);
+            for i in -20..20 {
+                let (x, y) = (f128::from(i) / 4.0, f128::from(i * i) / 7.0 - 3.0);
+                let mut bound = vec![("x".to_owned(), x), ("y".to_owned(), y)];
+                let interpreted = expression.eval(&env, &mut bound, &mut Vec::new());
+                assert_eq!(
+                    bits(&compiled(&[x, y])),
+                    bits(&interpreted),
+                    "{text} at x = {x:?}, y = {y:?}"
+                );
+            }
+            assert!(matches!(compiled(&[1.0]), Err(Error::ArgumentCount { .. })));
+        }
+    }
+
+    #[test]
+    fn shunting_yard_missing_operands() {
+        let env = Environment::default();
+        let plus = &OPERATORS[0];
+        let mut warnings = Vec::new();
+        assert_eq!(
+            shunting_yard(vec![1.0, 2.0], &[plus], &env, &mut warnings),
+            Ok(3.0)
+        );
+        assert_eq!(
+            shunting_yard(vec![1.0], &[plus], &env, &mut warnings),
+            Err(Error::UnexpectedEnd)
+        );
+        assert_eq!(
+            shunting_yard(vec![1.0, 2.0], &[], &env, &mut warnings),
+            Err(Error::UnexpectedEnd)
+        );
+        assert_eq!(
+            shunting_yard(Vec::new(), &[], &env, &mut warnings),
+            Err(Error::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn juxtaposition_binding() {
+        let env = Environment::default();
+        let shown = |text| parse_str(text, &env).unwrap().to_string();
+        assert_eq!(shown("1/2 x"), "(1 / (2 * x))");
+        assert_eq!(shown("2^3 x"), "(2 ^ (3 * x))");
+        assert_eq!(shown("2 x * y"), "(2 * (x * y))");
+        assert_eq!(shown("2 x / y"), "(2 * (x / y))");
+        assert_eq!(shown("2 x^2"), "(2 * (x ^ 2))");
+        assert_eq!(shown("2(x) * y"), "((2 * x) * y)");
+        assert_eq!(shown("2 x + y"), "((2 * x) + y)");
+        assert_eq!(shown("a - 2 x"), "(a - (2 * x))");
+    }
+
+    #[test]
+    fn tables() {
+        let env = Environment::default();
+        let table = |text: &str| table(text, &env);
+        assert_eq!(
+            table("table(x, 0, 3, x^2)"),
+            Some(Ok(vec![
+                (0.0, Ok(0.0)),
+                (1.0, Ok(1.0)),
+                (2.0, Ok(4.0)),
+                (3.0, Ok(9.0))
+            ]))
+        );
+        assert_eq!(
+            table("table(x, 0, 1, 0.25, x^2)"),
+            Some(Ok(vec![
+                (0.0, Ok(0.0)),
+                (0.25, Ok(0.0625)),
+                (0.5, Ok(0.25)),
+                (0.75, Ok(0.5625)),
+                (1.0, Ok(1.0))
+            ]))
+        );
+        // A step that does not land on the second bound stops short of it
+        assert_eq!(
+            table("table(x, 0, 1, 0.4, x)").map(|x| x.map(|x| x.len())),
+            Some(Ok(3))
+        );
+        assert_eq!(
+            table("table(x, -1, 1, 1/x)"),
+            Some(Ok(vec![
+                (-1.0, Ok(-1.0)),
+                (0.0, Err(Error::DivByZero)),
+                (1.0, Ok(1.0))
+            ]))
+        );
+        assert_eq!(table("table(x, 3, 1, x)"), Some(Ok(Vec::new())));
+        for bad in ["table(x, 0, 1, 0, x)", "table(x, 0, 1, -1, x)"] {
+            assert_eq!(table(bad), Some(Err(Error::DomainError)), "{bad}");
+        }
+        // Unlike an error in a row, an error in the bounds fails the whole table
+        assert_eq!(table("table(x, 0, 1/0, x)"), Some(Err(Error::DivByZero)));
+        let limit = env.summation_limit;
+        assert_eq!(
+            table(&format!("table(x, 1, {limit}, x)")).map(|x| x.map(|x| x.len())),
+            Some(Ok(limit as usize))
+        );
+        assert_eq!(
+            table(&format!("table(x, 0, {limit}, x)")),
+            Some(Err(Error::TooManyTerms))
+        );
+        for (text, expected, found) in [
+            ("table(x, 0, x)", 4, 3),
+            ("table(x, 0, 1, 0.5, x, 1)", 5, 6),
+        ] {
+            assert_eq!(
+                table(text),
+                Some(Err(Error::ArgumentCount {
+                    expected,
+                    found,
+                    pos: 5
+                })),
+                "{text}"
+            );
+        }
+        assert_eq!(table("table + 1"), None);
+        assert_eq!(table("2 + 3"), None);
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(eval("pctchange(100, 150)"), Ok(50.0));
+        assert_eq!(eval("pctchange(200, 150)"), Ok(-25.0));
+        assert_eq!(eval("pctchange(-50, 50)"), Ok(-200.0));
+        assert_eq!(eval("pctof(25, 200)"), Ok(12.5));
+        assert_eq!(eval("pctof(300, 200)"), Ok(150.0));
+        assert_eq!(eval("pctof(0, 200)"), Ok(0.0));
+        assert_eq!(eval("pctchange(0, 150)"), Err(Error::DomainError));
+        assert_eq!(eval("pctof(25, 0)"), Err(Error::DomainError));
+        assert_eq!(eval("pctof(25, 5 - 5)"), Err(Error::DomainError));
+    }
+
+    #[test]
+    fn warnings() {
+        let env = Environment::default();
+        let warnings = |text, env| eval_with_warnings(text, env).map(|x| x.1);
+        assert_eq!(warnings("2 + 3 * 4", &env), Ok(vec![]));
+        assert_eq!(
+            warnings("10^200 * 10^200", &env),
+            Ok(vec![Warning::Overflow])
+        );
+        let extended = Environment {
+            extended_digits: true,
+            ..Environment::default()
+        };
+        // A Value holds 10^400, which only overflows when shown as an f64
+        assert_eq!(warnings("10^200 * 10^200", &extended), Ok(vec![]));
+        assert_eq!(
+            warnings("10^4000 * 10^4000", &extended),
+            Ok(vec![Warning::Overflow])
+        );
+        assert_eq!(
+            warnings("1 + 10^-20 - 1", &env),
+            Ok(vec![Warning::PrecisionLoss])
+        );
+        assert_eq!(warnings("1 + 10^-20 - 2", &env), Ok(vec![]));
+        let ieee = Environment {
+            ieee_division: true,
+            ..Environment::default()
+        };
+        assert_eq!(warnings("0/0", &ieee), Ok(vec![Warning::NotFinite]));
+        assert_eq!(warnings("1/0", &ieee), Ok(vec![Warning::Overflow]));
+        assert_eq!(warnings("0/0", &env), Err(Error::DivByZero));
+    }
+
+    #[test]
+    fn expression_free_variables() {
+        let mut env = Environment::default();
+        let free = |text, env: &Environment| parse_str(text, env).unwrap().free_variables(env);
+        assert_eq!(free("sin(x) + a*y", &env), ["x", "a", "y"]);
+        assert_eq!(free("x y + x^2", &env), ["x", "y"]);
+        assert_eq!(free("sum(k, 1, n, k^2)", &env), ["n"]);
+        assert_eq!(free("integ(t, 0, t, t)", &env), ["t"]);
+        assert_eq!(free("2 pi + e + c", &env), Vec::<String>::new());
+        // Only the expression is read, so assignments to it count for nothing
+        assert_eq!(free("x += y", &env), ["y"]);
+        execute("a = 1", &mut env).unwrap();
+        assert_eq!(free("sin(x) + a*y", &env), ["x", "y"]);
+        env.functions.insert("f".to_owned(), Rc::new(|x| Ok(x * x)));
+        assert_eq!(free("f(x)", &env), ["x"]);
+    }
 }
@@ -4,8 +4,19 @@
 #![windows_subsystem = "windows"]
 #![feature(f128)]
 
-use eframe::egui::{self, Modifiers, TextBuffer, TextStyle, Ui};
-use expr::evaluate;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, BuildHasherDefault},
+    iter,
+    ops::Range,
+    time::{Duration, Instant, SystemTime},
+};
+
+use eframe::egui::{
+    self, text_edit::TextEditOutput, text_selection::CursorRange, Key, KeyboardShortcut, Modifiers,
+    TextBuffer, TextStyle, Ui,
+};
+use expr::{execute, AngleMode, Environment, LogBase, Value, Warning};
 
 pub mod expr;
 
@@ -19,96 +30,3824 @@ fn main() {
     .unwrap();
 }
 
+/// Longest an evaluation may take before it is abandoned
+const EVAL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the expression about to be evaluated stays highlighted before its result is inserted
+const HIGHLIGHT_SECONDS: f64 = 0.25;
+
+/// Shortcuts that insert a symbol at the cursor, each of which the evaluator understands
+const SYMBOL_SHORTCUTS: [(KeyboardShortcut, char); 4] = [
+    (KeyboardShortcut::new(Modifiers::ALT, Key::P), 'π'),
+    (KeyboardShortcut::new(Modifiers::ALT, Key::R), '√'),
+    (KeyboardShortcut::new(Modifiers::ALT, Key::Num8), '×'),
+    (KeyboardShortcut::new(Modifiers::ALT, Key::Minus), '±'),
+];
+
+/// Id of the note editor, so its focus can be checked before it is shown
+const EDITOR_ID: &str = "note";
+
+/// Name of the variable holding the active note's last result
+const ANS: &str = "ans";
+
+/// Default for how a result is inserted after its expression, with `{}` standing for the result
+const DEFAULT_RESULT_TEMPLATE: &str = " = {}";
+
+/// Characters that end the expression at the cursor unless configured otherwise
+const DEFAULT_TERMINATORS: &str = ":=";
+
+/// Whether `template` holds exactly one `{}` placeholder and no other braces
+fn is_valid_template(template: &str) -> bool {
+    template.matches("{}").count() == 1 && !template.replacen("{}", "", 1).contains(['{', '}'])
+}
+
+/// The text inserted after an expression for `result`, as laid out by `template`
+fn render_result(template: &str, result: &str) -> String {
+    template.replacen("{}", result, 1)
+}
+
+/// Byte length of a result laid out by `template` at the start of `text`, if there is one.
+///
+/// The result itself is an error like `<division by zero>`, a pair like `{1, -3}`, or else runs up
+/// to the text after the placeholder or, if there is none, to the next whitespace.
+fn existing_result(template: &str, text: &str) -> Option<usize> {
+    let (prefix, suffix) = template.split_once("{}")?;
+    let rest = text.strip_prefix(prefix)?;
+    let len = if rest.starts_with('<') {
+        rest.find('>')? + 1
+    } else if rest.starts_with('{') {
+        rest.find('}')? + 1
+    } else if suffix.is_empty() {
+        rest.find(char::is_whitespace).unwrap_or(rest.len())
+    } else {
+        rest.find(suffix)?
+    };
+    let result = &rest[..len];
+    (!result.is_empty() && !result.contains('\n') && rest[len..].starts_with(suffix))
+        .then_some(prefix.len() + len + suffix.len())
+}
+
+/// Evaluates the paragraph around the cursor, whose lines before the last set up its variables
+const PARAGRAPH_SHORTCUT: KeyboardShortcut =
+    KeyboardShortcut::new(Modifiers::CTRL.plus(Modifiers::ALT), Key::Enter);
+
+/// What to evaluate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EvalScope {
+    /// The selection, or the expression at the cursor
+    Expression,
+    /// The block of non-blank lines around the cursor
+    Paragraph,
+}
+
+/// Moves the cursor to the next result in the note, wrapping around to the first at the end
+const NEXT_RESULT_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::NONE, Key::F3);
+
+/// Moves the cursor to the next result like [`NEXT_RESULT_SHORTCUT`], then evaluates the expression
+/// before it again
+const REEVALUATE_NEXT_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::SHIFT, Key::F3);
+
+/// Inserts the current date and time at the cursor
+const DATE_SHORTCUT: KeyboardShortcut =
+    KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::D);
+
+/// Duplicates the lines the cursor is on below them
+const DUPLICATE_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::D);
+
+/// Undoes the last change the app made to the note, if nothing has been typed since
+const UNDO_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Z);
+
+/// Redoes the last change undone with [`UNDO_SHORTCUT`]
+const REDO_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Y);
+
+/// Opens and closes the quick calculation bar
+const QUICK_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::K);
+
+/// Name of the font family the editor uses when a font is chosen in Settings
+const EDITOR_FONT: &str = "editor";
+
+/// Fonts that come with egui, which can be chosen without a font file: the name egui registers
+/// each under, and a description
+const BUNDLED_FONTS: [(&str, &str); 2] = [
+    ("Hack", "Hack (monospace)"),
+    ("Ubuntu-Light", "Ubuntu Light"),
+];
+
+/// Id of the quick calculation bar's input
+const QUICK_ID: &str = "quick input";
+
+/// Most changes kept for undoing
+const HISTORY_LIMIT: usize = 100;
+
+/// A change to the note made at the cursor
+enum Edit {
+    /// Replace the selection with a symbol
+    Insert(char),
+    /// Wrap the selection in parentheses after a function name, which may be empty
+    Wrap(String),
+    /// Replace the selection with the current date and time
+    Date,
+    /// Move the cursor to the start of the next result, then with `evaluate`, evaluate the
+    /// expression there again. The note is left as it is, so this is allowed in read-only notes.
+    NextResult {
+        evaluate: bool,
+    },
+    DuplicateLines,
+    /// Add up the lines the selection touches below them, with `running`, also after each line
+    Total {
+        running: bool,
+    },
+    /// Add up the rows and columns of the tab-separated grid the selection touches below it
+    TotalGrid,
+    /// Follow each line the selection touches with its share of their total
+    PercentOfTotal,
+    /// Follow each line the selection touches with a cell computed down the column
+    Sequence(Sequence),
+    /// Restore the note as it was before the last change in [`History`]
+    Undo,
+    /// Restore the note as it was after the last undone change
+    Redo,
+}
+
+/// What [`sequence_column`] computes for each number down a column
+#[derive(Clone, Copy)]
+enum Sequence {
+    /// The difference from the number above, as `diff` does
+    Differences,
+    /// The sum of the numbers so far, as `cumsum` does
+    RunningSums,
+}
+
+/// The note and the editor's selection at one moment
+#[derive(Clone)]
+struct Snapshot {
+    text: String,
+    selection: Option<egui::text::CCursorRange>,
+}
+
+/// A change the app made to the note, as opposed to one typed into the editor
+struct Change {
+    before: Snapshot,
+    after: Snapshot,
+}
+
+/// Changes the app made to the note: inserted results, symbols, wrapped selections, duplicated
+/// lines, and applied precedence hints.
+///
+/// These are undone separately from typing, which the editor undoes itself. A change can only be
+/// undone while the note is as the change left it, so anything typed after it is never lost.
 #[derive(Default)]
+struct History {
+    undo: VecDeque<Change>,
+    redo: Vec<Change>,
+}
+
+impl History {
+    /// Remember a change so that it can be undone, forgetting the oldest one past [`HISTORY_LIMIT`]
+    fn record(&mut self, before: Snapshot, after: Snapshot) {
+        if before.text == after.text {
+            return;
+        }
+        self.redo.clear();
+        if self.undo.len() == HISTORY_LIMIT {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(Change { before, after });
+    }
+
+    /// Whether the last change can be undone from the note `text`
+    fn can_undo(&self, text: &str) -> bool {
+        self.undo.back().is_some_and(|x| x.after.text == text)
+    }
+
+    /// Whether the last undone change can be redone from the note `text`
+    fn can_redo(&self, text: &str) -> bool {
+        self.redo.last().is_some_and(|x| x.before.text == text)
+    }
+
+    /// Move the last change to the redo stack, returning the note as it was before it
+    fn undo(&mut self) -> Option<&Snapshot> {
+        let change = self.undo.pop_back()?;
+        self.redo.push(change);
+        self.redo.last().map(|x| &x.before)
+    }
+
+    /// Move the last undone change back to the undo stack, returning the note as it was after it
+    fn redo(&mut self) -> Option<&Snapshot> {
+        let change = self.redo.pop()?;
+        self.undo.push_back(change);
+        self.undo.back().map(|x| &x.after)
+    }
+}
+
+/// An evaluation waiting for its expression's highlight to finish
+struct PendingEval {
+    scope: EvalScope,
+    /// Character range of the expression within the note
+    range: Range<usize>,
+    /// The expression text, used to notice edits made while it was highlighted
+    text: String,
+    /// Time at which the result is inserted
+    at: f64,
+}
+
+/// An evaluated expression whose grouping is easy to misread, offered with explicit parentheses
+struct PrecedenceHint {
+    /// Character range of the expression within the note
+    range: Range<usize>,
+    /// The expression text, used to notice edits made since it was evaluated
+    text: String,
+    /// The fully parenthesized form of the expression
+    parenthesized: String,
+}
+
+/// Separator between notes in the persisted `notes_list` string
+const NOTE_SEPARATOR: char = '\x02'; // non-printable
+
+/// Most bytes of notes that are saved. Browsers commonly give `localStorage` 5 MB per site, which
+/// must also hold the previous save kept beside the latest one.
+const SAVE_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Bytes of notes past which the user is warned that they are nearing [`SAVE_LIMIT`]
+const SAVE_WARNING: usize = SAVE_LIMIT / 4 * 3;
+
+#[allow(clippy::struct_excessive_bools)]
 struct NotesApp {
     notes_list: Vec<String>,
+    /// Index of the note being edited
+    active: usize,
+    /// Indices of notes that evaluation must not modify; their results go to `scratch` instead
+    read_only: HashSet<usize>,
+    /// Results of evaluating read-only notes, one `expression = result` per line
+    scratch: String,
+    /// Whether the quick calculation bar is shown
+    quick_open: bool,
+    /// The expression in the quick calculation bar
+    quick_input: String,
+    /// The result of `quick_input`, updated as it is typed
+    quick_result: String,
+    /// Variables assigned by evaluating statements in each note, keyed by note index, so that one
+    /// note's variables are never visible from another
+    variables: HashMap<usize, HashMap<String, Value>>,
     settings_open: bool,
+    /// Whether the parse tree of `last_expression` is shown
+    tree_open: bool,
+    help_open: bool,
     fixed_width: bool,
+    /// The editor's font: empty for egui's default, one of [`BUNDLED_FONTS`], or a font file's path
+    font: String,
+    /// The font file path as typed in Settings, which only replaces `font` once loaded
+    font_path_draft: String,
+    angle_mode: AngleMode,
+    log_base: LogBase,
+    /// Number of decimal places results are inserted with, or `None` for as many as needed
+    precision: Option<usize>,
+    summation_limit: u32,
+    integration_steps: u32,
+    programmer_mode: bool,
+    ieee_division: bool,
+    lenient_trailing: bool,
+    strict_identifiers: bool,
+    scientific_above: i32,
+    scientific_below: i32,
+    /// Whether results are shown with every significant digit the evaluation keeps, rather than
+    /// only those an `f64` holds
+    extended_digits: bool,
+    /// Characters besides line breaks that an expression at the cursor cannot extend past
+    terminators: String,
+    /// Text inserted after an evaluated expression, with `{}` replaced by the result
+    result_template: String,
+    /// Whether results go on a new line below their expression rather than after it
+    result_on_new_line: bool,
+    /// Whether evaluating only shows the result above the note, never changing the note
+    calculator_only: bool,
+    /// The expression last evaluated under `calculator_only`, with its result
+    shown_result: Option<String>,
+    /// Whether the results in the notes are evaluated again when they are loaded, so that they are
+    /// current even if the notes were edited elsewhere
+    recompute_on_open: bool,
+    /// Whether the results in the note are colored by their sign, to make columns of figures easier
+    /// to scan
+    sign_colors: bool,
+    /// Whether Shift+Enter evaluates like Ctrl+Enter, rather than starting a new line
+    shift_enter_evaluates: bool,
+    /// Whether numbers like `3,14` in pasted text are read as decimals, as in European sources
+    decimal_comma_paste: bool,
+    /// The template as typed in Settings, which only replaces `result_template` once valid
+    template_draft: String,
+    /// Message shown to the user until dismissed, e.g. after a failed load
+    notice: Option<String>,
+    pending: Option<PendingEval>,
+    hint: Option<PrecedenceHint>,
+    /// Numerical pitfalls met by the last evaluation
+    warnings: Vec<Warning>,
+    /// The last successful result, as inserted
+    last_result: Option<String>,
+    /// The statement evaluated last
+    last_expression: Option<String>,
+    /// The editor's selection as of the previous frame, restored when a right click collapses it
+    selection: Option<egui::text::CCursorRange>,
+    /// Unreadable `notes_list` data, kept under `notes_list.bak` so it is never silently lost
+    backup: Option<String>,
+    /// Whether the user has been warned that the notes are nearing [`SAVE_LIMIT`], so that the
+    /// warning is not repeated at every save
+    size_warned: bool,
+    history: History,
+    /// Hashes of lines whose result was inserted this session, as they were when it was inserted
+    fresh_lines: HashSet<u64>,
+    /// Hashes of lines that were edited after their result was inserted, so that the result is
+    /// shown as out of date until the line is evaluated again
+    stale_lines: HashSet<u64>,
+    /// The note as of the end of the last frame, to find the lines edited since
+    shown: String,
+    /// Byte range in the note of where the expression evaluated last could not be read, with why,
+    /// until the note is edited
+    parse_error: Option<(Range<usize>, String)>,
+}
+
+impl Default for NotesApp {
+    fn default() -> Self {
+        Self {
+            notes_list: vec![String::new()],
+            active: 0,
+            read_only: HashSet::new(),
+            scratch: String::new(),
+            quick_open: false,
+            quick_input: String::new(),
+            quick_result: String::new(),
+            variables: HashMap::new(),
+            settings_open: false,
+            tree_open: false,
+            help_open: false,
+            fixed_width: false,
+            font: String::new(),
+            font_path_draft: String::new(),
+            angle_mode: AngleMode::Radians,
+            log_base: LogBase::Ten,
+            precision: None,
+            summation_limit: expr::DEFAULT_SUMMATION_LIMIT,
+            integration_steps: expr::DEFAULT_INTEGRATION_STEPS,
+            programmer_mode: false,
+            ieee_division: false,
+            lenient_trailing: false,
+            strict_identifiers: false,
+            scientific_above: expr::DEFAULT_SCIENTIFIC_ABOVE,
+            scientific_below: expr::DEFAULT_SCIENTIFIC_BELOW,
+            extended_digits: false,
+            terminators: DEFAULT_TERMINATORS.to_owned(),
+            result_template: DEFAULT_RESULT_TEMPLATE.to_owned(),
+            result_on_new_line: false,
+            calculator_only: false,
+            shown_result: None,
+            recompute_on_open: false,
+            sign_colors: false,
+            shift_enter_evaluates: true,
+            decimal_comma_paste: false,
+            template_draft: DEFAULT_RESULT_TEMPLATE.to_owned(),
+            notice: None,
+            pending: None,
+            hint: None,
+            warnings: Vec::new(),
+            last_result: None,
+            last_expression: None,
+            selection: None,
+            backup: None,
+            size_warned: false,
+            history: History::default(),
+            fresh_lines: HashSet::new(),
+            stale_lines: HashSet::new(),
+            shown: String::new(),
+            parse_error: None,
+        }
+    }
+}
+
+/// Whether a note can hold `c`, which excludes control characters other than line breaks and tabs
+fn storable(c: char) -> bool {
+    !c.is_control() || matches!(c, '\n' | '\r' | '\t')
+}
+
+/// Split persisted `notes_list` data back into notes, dropping any characters a note cannot hold.
+///
+/// Whether the data is complete is up to its checksum, as checked by [`intact`].
+fn parse_notes_list(data: &str) -> Vec<String> {
+    data.split(NOTE_SEPARATOR)
+        .map(|x| x.chars().filter(|&c| storable(c)).collect())
+        .collect()
+}
+
+/// A checksum of saved data, so a write cut off partway can be told apart from a complete one
+fn checksum(data: &str) -> String {
+    // 64-bit FNV-1a, which unlike `DefaultHasher` is the same in every build
+    let hash = data.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, x| {
+        (hash ^ u64::from(x)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// Whether `data`, saved under `key`, matches the checksum saved alongside it.
+///
+/// Data saved before checksums were kept has none, and is trusted.
+fn intact(storage: &dyn eframe::Storage, key: &str, data: &str) -> bool {
+    storage
+        .get_string(&format!("{key}.checksum"))
+        .is_none_or(|x| x == checksum(data))
 }
 
 impl NotesApp {
     fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        cc.storage.map_or_else(Self::default, |storage| Self {
-            notes_list: storage
-                .get_string("notes_list")
-                .map(|x| x.split("\x02").map(str::to_owned).collect())
-                .unwrap_or_else(|| vec![storage.get_string("notes_text").unwrap_or_default()]),
-            settings_open: false,
-            fixed_width: matches!(storage.get_string("fixed_width").as_deref(), Some("true")),
+        let mut app = cc.storage.map_or_else(Self::default, Self::restore);
+        app.use_font(&cc.egui_ctx, app.font.clone());
+        app
+    }
+
+    /// The app as it was saved in `storage`
+    fn restore(storage: &dyn eframe::Storage) -> Self {
+        let flag = |key| matches!(storage.get_string(key).as_deref(), Some("true"));
+        let mut app = Self {
+            fixed_width: flag("fixed_width"),
+            angle_mode: if flag("degrees") {
+                AngleMode::Degrees
+            } else {
+                AngleMode::Radians
+            },
+            log_base: if flag("natural_log") {
+                LogBase::Natural
+            } else {
+                LogBase::Ten
+            },
+            precision: storage.get_string("precision").and_then(|x| x.parse().ok()),
+            programmer_mode: flag("programmer_mode"),
+            lenient_trailing: flag("lenient_trailing"),
+            strict_identifiers: flag("strict_identifiers"),
+            result_on_new_line: flag("result_on_new_line"),
+            calculator_only: flag("calculator_only"),
+            recompute_on_open: flag("recompute_on_open"),
+            sign_colors: flag("sign_colors"),
+            ieee_division: flag("ieee_division"),
+            shift_enter_evaluates: !matches!(
+                storage.get_string("shift_enter_evaluates").as_deref(),
+                Some("false")
+            ),
+            decimal_comma_paste: flag("decimal_comma_paste"),
+            summation_limit: storage
+                .get_string("summation_limit")
+                .and_then(|x| x.parse().ok())
+                .map_or(expr::DEFAULT_SUMMATION_LIMIT, |x: u32| {
+                    x.clamp(
+                        *expr::SUMMATION_LIMITS.start(),
+                        *expr::SUMMATION_LIMITS.end(),
+                    )
+                }),
+            integration_steps: storage
+                .get_string("integration_steps")
+                .and_then(|x| x.parse().ok())
+                .map_or(expr::DEFAULT_INTEGRATION_STEPS, |x: u32| {
+                    x.clamp(
+                        *expr::INTEGRATION_STEPS.start(),
+                        *expr::INTEGRATION_STEPS.end(),
+                    )
+                }),
+            scientific_above: storage
+                .get_string("scientific_above")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(expr::DEFAULT_SCIENTIFIC_ABOVE),
+            scientific_below: storage
+                .get_string("scientific_below")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(expr::DEFAULT_SCIENTIFIC_BELOW),
+            extended_digits: flag("extended_digits"),
+            terminators: storage
+                .get_string("terminators")
+                .unwrap_or_else(|| DEFAULT_TERMINATORS.to_owned()),
+            font: storage.get_string("font").unwrap_or_default(),
+            ..Self::default()
+        };
+        app.read_only = storage
+            .get_string("read_only")
+            .map(|x| x.split(',').filter_map(|x| x.parse().ok()).collect())
+            .unwrap_or_default();
+        if let Some(template) = storage
+            .get_string("result_template")
+            .filter(|x| is_valid_template(x))
+        {
+            app.template_draft.clone_from(&template);
+            app.result_template = template;
+        }
+        app.load_notes(storage);
+        if app.recompute_on_open {
+            app.recompute_notes();
+        }
+        app
+    }
+
+    /// The font the note is shown in
+    fn editor_font(&self, ui: &Ui) -> egui::FontId {
+        let style = if self.fixed_width {
+            TextStyle::Monospace
+        } else {
+            TextStyle::Body
+        };
+        let mut font_id = style.resolve(ui.style());
+        // A newly chosen font is only registered from the next frame on
+        let family = egui::FontFamily::Name(EDITOR_FONT.into());
+        if !self.font.is_empty() && ui.fonts(|x| x.families().contains(&family)) {
+            font_id.family = family;
+        }
+        font_id
+    }
+
+    /// Make `font` the editor's font, falling back to the default font with a notice if it cannot
+    /// be loaded
+    fn use_font(&mut self, ctx: &egui::Context, font: String) {
+        let mut fonts = egui::FontDefinitions::default();
+        let loaded = font.is_empty() || fonts.font_data.contains_key(&font) || {
+            match std::fs::read(&font) {
+                Ok(data) if is_font(&data) => {
+                    fonts
+                        .font_data
+                        .insert(font.clone(), egui::FontData::from_owned(data));
+                    true
+                }
+                _ => false,
+            }
+        };
+        if !loaded {
+            self.notice = Some(format!(
+                "Could not load the font {font}, so the default font is used instead."
+            ));
+            self.font.clear();
+            ctx.set_fonts(fonts);
+            return;
+        }
+        if !font.is_empty() {
+            // The default fonts come after it, for the characters it lacks
+            let fallbacks = &fonts.families[&egui::FontFamily::Proportional];
+            let family = iter::once(font.clone())
+                .chain(fallbacks.iter().filter(|&x| *x != font).cloned())
+                .collect();
+            fonts
+                .families
+                .insert(egui::FontFamily::Name(EDITOR_FONT.into()), family);
+        }
+        self.font = font;
+        ctx.set_fonts(fonts);
+    }
+
+    /// Save the notes, keeping the previous save beside them.
+    ///
+    /// Notes past [`SAVE_LIMIT`] are not saved, as the write could fail partway or push out other
+    /// data; the last complete save is kept instead, and the user is told. Nearing the limit, they
+    /// are warned once.
+    fn save_notes(&mut self, storage: &mut dyn eframe::Storage) {
+        // Leaving out what a note cannot hold keeps a pasted control character from splitting a
+        // note in two on the next load
+        let data = self
+            .notes_list
+            .iter()
+            .map(|x| x.chars().filter(|&c| storable(c)).collect::<String>())
+            .collect::<Vec<_>>()
+            .join(&NOTE_SEPARATOR.to_string());
+        if data.len() > SAVE_LIMIT {
+            self.notice = Some(format!(
+                "Your notes are too large to save: {} KB, past the limit of {} KB. Your last save \
+                 has been kept, but changes since then will be lost unless you remove some text \
+                 or notes.",
+                data.len().div_ceil(1024),
+                SAVE_LIMIT / 1024
+            ));
+            return;
+        }
+        if data.len() <= SAVE_WARNING {
+            self.size_warned = false;
+        } else if !self.size_warned {
+            self.notice = Some(format!(
+                "Your notes take up {} KB, nearing the limit of {} KB that can be saved. Consider \
+                 removing some text or notes.",
+                data.len().div_ceil(1024),
+                SAVE_LIMIT / 1024
+            ));
+            self.size_warned = true;
+        }
+        // Keep the last complete save, so there is something to fall back to if this one is cut off
+        if let Some(previous) = storage
+            .get_string("notes_list")
+            .filter(|x| intact(storage, "notes_list", x))
+        {
+            storage.set_string("notes_list.prev.checksum", checksum(&previous));
+            storage.set_string("notes_list.prev", previous);
+        }
+        storage.set_string("notes_list.checksum", checksum(&data));
+        storage.set_string("notes_list", data);
+    }
+
+    /// Restore the saved notes, falling back to the previous save if the latest one was only
+    /// partly written.
+    ///
+    /// The single note of the legacy `notes_text` key is only read when there is no `notes_list`.
+    fn load_notes(&mut self, storage: &dyn eframe::Storage) {
+        if let Some(data) = storage.get_string("notes_list") {
+            let previous = || {
+                storage
+                    .get_string("notes_list.prev")
+                    .filter(|x| intact(storage, "notes_list.prev", x))
+                    .map(|x| parse_notes_list(&x))
+            };
+            if intact(storage, "notes_list", &data) {
+                self.notes_list = parse_notes_list(&data);
+            } else if let Some(notes_list) = previous() {
+                self.notes_list = notes_list;
+                self.notice = Some(
+                    "Your latest notes were not saved completely, so the previous save was \
+                     restored. The incomplete data was kept under `notes_list.bak`."
+                        .to_owned(),
+                );
+                self.backup = Some(data);
+            } else {
+                self.notice = Some(
+                    "Your saved notes could not be read, so a fresh note was started. \
+                     The unreadable data was kept under `notes_list.bak`."
+                        .to_owned(),
+                );
+                self.backup = Some(data);
+            }
+        } else {
+            // Saved before there could be several notes; `save` writes it back as `notes_list`
+            let legacy = storage.get_string("notes_text").unwrap_or_default();
+            self.notes_list = vec![legacy.chars().filter(|&c| storable(c)).collect()];
+        }
+        // Notes may have been removed since the index was saved
+        self.active = storage
+            .get_string("active_note")
+            .and_then(|x| x.parse().ok())
+            .map_or(0, |x: usize| x.min(self.notes_list.len() - 1));
+    }
+
+    /// Evaluate each note again, replacing results that have changed, and tell the user how many
+    /// lines were changed, if any
+    fn recompute_notes(&mut self) {
+        let (env, terminators) = (self.parse_environment(), self.terminators());
+        let mut changed = 0;
+        for note in &mut self.notes_list {
+            let mut env = env.clone();
+            env.cells = note_cells_values(note, &self.result_template, &env);
+            let (recomputed, lines) =
+                recompute_results(note, &mut env, &self.result_template, &terminators);
+            *note = recomputed;
+            changed += lines;
+        }
+        self.notice = match changed {
+            0 => self.notice.take(),
+            1 => Some("The result on 1 line was out of date and has been recomputed.".to_owned()),
+            _ => Some(format!(
+                "The results on {changed} lines were out of date and have been recomputed."
+            )),
+        };
+    }
+
+    /// Take the key press asking for an evaluation, if there is one, and what it evaluates
+    fn take_eval(&self, input: &mut egui::InputState) -> Option<EvalScope> {
+        // Checked first, as Ctrl+Enter would also match it
+        if input.consume_shortcut(&PARAGRAPH_SHORTCUT) {
+            Some(EvalScope::Paragraph)
+        } else {
+            // Left unconsumed, Shift+Enter starts a new line in the editor
+            (input.consume_key(Modifiers::CTRL, egui::Key::Enter)
+                || (self.shift_enter_evaluates
+                    && input.consume_key(Modifiers::SHIFT, egui::Key::Enter)))
+            .then_some(EvalScope::Expression)
+        }
+    }
+
+    /// Restore the selection of the previous frame when a right click on the editor has collapsed
+    /// it, so that the context menu acts on it
+    fn keep_selection(&self, ui: &Ui, output: &mut TextEditOutput) {
+        if output.response.hovered() && ui.input(|x| x.pointer.secondary_pressed()) {
+            if let Some(selection) = self.selection {
+                output.state.cursor.set_char_range(Some(selection));
+                output.state.clone().store(ui.ctx(), output.response.id);
+                output.cursor_range = Some(CursorRange {
+                    primary: output.galley.from_ccursor(selection.primary),
+                    secondary: output.galley.from_ccursor(selection.secondary),
+                });
+            }
+        }
+    }
+
+    /// Show the note being edited, evaluating what `eval` asks for at the cursor and making `edit`
+    /// there
+    fn editor(
+        &mut self,
+        ui: &mut Ui,
+        mut eval: Option<EvalScope>,
+        mut edit: Option<Edit>,
+        read_only: bool,
+    ) -> egui::Response {
+        let font_id = self.editor_font(ui);
+        // A `&str` buffer can still be selected in, but not edited
+        let mut text = self.notes_list[self.active].as_str();
+        let buffer: &mut dyn TextBuffer = if read_only {
+            &mut text
+        } else {
+            &mut self.notes_list[self.active]
+        };
+        let (stale_lines, result_template) = (&self.stale_lines, &self.result_template);
+        let sign_colors = self.sign_colors;
+        let error = self.parse_error.as_ref().map(|x| x.0.clone());
+        let mut layouter = |ui: &Ui, text: &str, wrap_width: f32| {
+            let mut job = note_layout(
+                ui,
+                text,
+                font_id.clone(),
+                stale_lines,
+                result_template,
+                sign_colors,
+            );
+            if let Some(error) = error.clone().filter(|x| x.end <= text.len()) {
+                mark_error(ui, &mut job, error);
+            }
+            job.wrap.max_width = wrap_width;
+            ui.fonts(|x| x.layout_job(job))
+        };
+        let text_edit = egui::TextEdit::multiline(buffer)
+            .id(egui::Id::new(EDITOR_ID))
+            .layouter(&mut layouter);
+        let mut output = text_edit.show(ui);
+        self.keep_selection(ui, &mut output);
+        self.selection = output.cursor_range.map(|x| x.as_ccursor_range());
+        let selected = output.cursor_range.is_some_and(|x| !x.is_empty());
+        output.response.context_menu(|ui| {
+            let (menu_eval, menu_edit) = self.context_menu(ui, selected, read_only);
+            eval = eval.or(menu_eval);
+            edit = edit.take().or(menu_edit);
+        });
+        let now = ui.input(|x| x.time);
+        let template = if read_only || self.calculator_only {
+            self.result_template.clone()
+        } else {
+            self.note_template()
+        };
+        if let Some(edit) = edit.filter(|x| !read_only || matches!(x, Edit::NextResult { .. })) {
+            self.apply(edit, &mut output);
+            output.state.clone().store(ui.ctx(), output.response.id);
+        }
+        // The environment is only made when something is evaluated, as working out the note's
+        // cells for it takes too long to do every frame
+        if let Some(scope) = eval {
+            let env = self.environment();
+            let range = output.cursor_range.map(|x| self.eval_range(scope, x, &env));
+            // There is no paragraph to evaluate on a blank line
+            if let Some(range) = range.filter(|x| scope == EvalScope::Expression || !x.is_empty()) {
+                set_selection(&mut output, range.clone());
+                output.state.clone().store(ui.ctx(), output.response.id);
+                self.pending = Some(PendingEval {
+                    scope,
+                    text: self.notes_list[self.active]
+                        .char_range(range.clone())
+                        .to_owned(),
+                    range,
+                    at: now + HIGHLIGHT_SECONDS,
+                });
+                ui.ctx()
+                    .request_repaint_after(Duration::from_secs_f64(HIGHLIGHT_SECONDS));
+            }
+        }
+        let mut parse_error = None;
+        if let Some(pending) = self.pending.take_if(|x| now >= x.at) {
+            // Skip the evaluation if the expression was edited while highlighted
+            if self.notes_list[self.active].char_range(pending.range.clone()) == pending.text {
+                let env = self.environment();
+                parse_error = self.parse_error_at(&pending, &env);
+                self.hint = expr::precedence_hint(&pending.text, &env)
+                    .filter(|_| !read_only && pending.scope == EvalScope::Expression)
+                    .map(|x| PrecedenceHint {
+                        range: pending.range.clone(),
+                        text: pending.text.clone(),
+                        parenthesized: x,
+                    });
+                let insertion = self.insertion(&pending, env, &template);
+                if read_only {
+                    self.scratch.push_str(&pending.text);
+                    self.scratch.push_str(&insertion);
+                    self.scratch.push('\n');
+                } else if self.calculator_only {
+                    let last_line = pending.text.lines().last().unwrap_or_default();
+                    self.shown_result = Some(format!("{}{insertion}", last_line.trim()));
+                    let end = pending.range.end;
+                    set_selection(&mut output, end..end);
+                    output.state.clone().store(ui.ctx(), output.response.id);
+                } else {
+                    self.insert_result(pending.range.end, &insertion, &template, &mut output);
+                    output.state.clone().store(ui.ctx(), output.response.id);
+                }
+            }
+        }
+        self.track_results();
+        // Set after the result is inserted, which would otherwise clear it as an edit
+        if parse_error.is_some() {
+            self.parse_error = parse_error;
+            ui.ctx().request_repaint();
+        }
+        output.response
+    }
+
+    /// Make `edit` to the note at the editor's cursor, recording it in `history` unless it steps
+    /// through the history itself
+    fn apply(&mut self, edit: Edit, output: &mut TextEditOutput) {
+        let env = self.environment();
+        let note = &mut self.notes_list[self.active];
+        let before = snapshot(note, output);
+        match edit {
+            Edit::Insert(symbol) => insert_text(note, output, &symbol.to_string()),
+            Edit::Date => insert_text(note, output, &format_date(SystemTime::now())),
+            Edit::Wrap(name) => wrap_selection(note, output, &name),
+            Edit::DuplicateLines => duplicate_lines(note, output),
+            Edit::Total { running } => total_lines(note, output, &env, running),
+            Edit::TotalGrid => total_grid(note, output, &env),
+            Edit::PercentOfTotal => percent_of_total(note, output, &env),
+            Edit::Sequence(sequence) => sequence_column(note, output, &env, sequence),
+            Edit::NextResult { .. } => next_result(note, output, &self.result_template),
+            Edit::Undo | Edit::Redo => {
+                let restored = if matches!(edit, Edit::Undo) {
+                    self.history.undo()
+                } else {
+                    self.history.redo()
+                };
+                if let Some(restored) = restored {
+                    note.clone_from(&restored.text);
+                    output.state.cursor.set_char_range(restored.selection);
+                }
+                return;
+            }
+        }
+        self.history.record(before, snapshot(note, output));
+    }
+
+    /// Insert `insertion` into the note after the expression ending at character index `end`,
+    /// leaving the cursor after it
+    fn insert_result(
+        &mut self,
+        end: usize,
+        insertion: &str,
+        template: &str,
+        output: &mut TextEditOutput,
+    ) {
+        let note = &mut self.notes_list[self.active];
+        let before = snapshot(note, output);
+        let at = note.byte_index_from_char_index(end);
+        // Replace the result of an earlier evaluation rather than adding another, and leave the
+        // note untouched if the result has not changed
+        let len = existing_result(template, &note[at..]).unwrap_or(0);
+        if note[at..at + len] != *insertion {
+            note.replace_range(at..at + len, insertion);
+        }
+        let end = end + insertion.chars().count();
+        set_selection(output, end..end);
+        self.history.record(before, snapshot(note, output));
+        let line = line_hash(line_around(note, at + insertion.len()));
+        self.stale_lines.remove(&line);
+        self.fresh_lines.insert(line);
+    }
+
+    /// Mark the results on lines edited since the last frame as out of date, if they were inserted
+    /// for the line as it was.
+    ///
+    /// Results inserted on a line of their own are not tracked, since editing the expression above
+    /// leaves the result's line as it was.
+    fn track_results(&mut self) {
+        let note = &self.notes_list[self.active];
+        if *note == self.shown {
+            return;
+        }
+        self.parse_error = None;
+        let (old, new) = (self.shown.as_str(), note.as_str());
+        let prefix = old
+            .char_indices()
+            .zip(new.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or_else(|| old.len().min(new.len()), |((i, _), _)| i);
+        let suffix = old
+            .chars()
+            .rev()
+            .zip(new.chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(x, _)| x.len_utf8())
+            .sum::<usize>()
+            .min(old.len() - prefix)
+            .min(new.len() - prefix);
+        let tracked = whole_lines(old, prefix, old.len() - suffix)
+            .lines()
+            .map(line_hash)
+            .any(|x| self.fresh_lines.contains(&x) || self.stale_lines.contains(&x));
+        if tracked {
+            for line in whole_lines(new, prefix, new.len() - suffix).lines() {
+                let hash = line_hash(line);
+                let result = trailing_result(&self.result_template, line);
+                if result.is_some() && !self.fresh_lines.contains(&hash) {
+                    self.stale_lines.insert(hash);
+                }
+            }
+        }
+        // Results that depend on a cell whose formula was edited are out of date too, wherever they
+        // are, while inserting a cell's own result changes nothing
+        let template = &self.result_template;
+        let before = note_cells(whole_lines(old, prefix, old.len() - suffix), template);
+        let after = note_cells(whole_lines(new, prefix, new.len() - suffix), template);
+        let cells = (before.iter().filter(|x| !after.contains(x)))
+            .chain(after.iter().filter(|x| !before.contains(x)))
+            .map(|(name, _)| (*name).to_owned())
+            .collect::<Vec<_>>();
+        if !cells.is_empty() {
+            let dependents = dependent_lines(new, template, &self.terminators(), cells);
+            self.stale_lines.extend(dependents);
+        }
+        let present: HashSet<u64> = new.lines().map(line_hash).collect();
+        self.stale_lines.retain(|x| present.contains(x));
+        self.shown.clone_from(note);
+    }
+
+    /// Where and why `pending` could not be read, if it is an expression that does not evaluate,
+    /// as a byte range in the note and a message
+    fn parse_error_at(
+        &self,
+        pending: &PendingEval,
+        env: &Environment,
+    ) -> Option<(Range<usize>, String)> {
+        if pending.scope != EvalScope::Expression {
+            return None;
+        }
+        let error = expr::parse_str(&pending.text, env).err()?;
+        if evaluates(&pending.text, env) {
+            return None;
+        }
+        let start = self.notes_list[self.active].byte_index_from_char_index(pending.range.start);
+        let range = error_range(&pending.text, error.pos);
+        Some((start + range.start..start + range.end, error.message))
+    }
+
+    /// Character range of what to evaluate in the note for `scope` and the editor's `cursor`
+    fn eval_range(&self, scope: EvalScope, cursor: CursorRange, env: &Environment) -> Range<usize> {
+        let note = &self.notes_list[self.active];
+        match scope {
+            EvalScope::Expression => {
+                let (primary, secondary) =
+                    (cursor.primary.ccursor.index, cursor.secondary.ccursor.index);
+                // Leave out a result already inserted after the cursor, which a template need not
+                // start with a terminator to mark, and which may read as part of the expression.
+                // With the cursor in or after a result that is still up to date, as it is after
+                // evaluating, the expression is the one before it, so evaluating again changes
+                // nothing.
+                let end = note.byte_index_from_char_index(primary.max(secondary));
+                let line_start = note[..end].rfind('\n').map_or(0, |x| x + 1);
+                let line = whole_lines(note, end, end);
+                let terminators = self.terminators();
+                let (note, primary, secondary) = trailing_result(&self.result_template, line)
+                    .filter(|&x| {
+                        line_start + x >= end
+                            || shows_result(line, x, &self.result_template, &terminators, env)
+                    })
+                    .map_or((note.as_str(), primary, secondary), |x| {
+                        let note = &note[..line_start + x];
+                        let limit = note.chars().count();
+                        (note, primary.min(limit), secondary.min(limit))
+                    });
+                expression_range(note, primary, secondary, &terminators, |x| {
+                    evaluates(x, env)
+                })
+            }
+            EvalScope::Paragraph => paragraph_range(note, cursor.primary.ccursor.index),
+        }
+    }
+
+    /// Evaluate `pending`, returning the text to insert after it
+    fn insertion(&mut self, pending: &PendingEval, mut env: Environment, template: &str) -> String {
+        if let Some(digits) = expr::base(&pending.text, &env) {
+            return render_result(template, &digits.unwrap_or_else(|x| x.to_string()));
+        }
+        match expr::table(&pending.text, &env) {
+            Some(Ok(rows)) => format_table(&rows, &env),
+            Some(Err(x)) => render_result(template, &x.to_string()),
+            None if pending.scope == EvalScope::Paragraph => {
+                let (setup, last) = pending
+                    .text
+                    .rsplit_once('\n')
+                    .unwrap_or(("", &pending.text));
+                expr::evaluate_document(setup, &mut env);
+                render_result(template, &self.evaluate(last, env))
+            }
+            None => render_result(template, &self.evaluate(&pending.text, env)),
+        }
+    }
+
+    /// Evaluate `text` in `env`, returning the formatted result and keeping any variables it sets
+    fn evaluate(&mut self, text: &str, mut env: Environment) -> String {
+        self.last_expression = Some(text.to_owned());
+        // Neither of a `±`'s results is the result, so `ans` is left as it was
+        if let Some(result) = expr::eval_plus_minus(text, &env) {
+            self.warnings.clear();
+            return match result {
+                Ok((plus, minus)) => {
+                    let formatted = format_plus_minus(plus, minus, &env);
+                    self.last_result = Some(formatted.clone());
+                    formatted
+                }
+                Err(x) => x.to_string(),
+            };
+        }
+        let result = expr::execute_with_warnings(text, &mut env);
+        self.warnings = result.as_ref().map(|x| x.1.clone()).unwrap_or_default();
+        let result = result.map(|x| x.0);
+        let formatted = format_result(result.clone(), &env);
+        if let Ok(x) = result {
+            self.last_result = Some(formatted.clone());
+            // Only the inserted text is rounded; later expressions see the exact value
+            env.variables.insert(ANS.to_owned(), x);
+        }
+        self.variables.insert(self.active, env.variables);
+        formatted
+    }
+
+    /// Fill the editor's right-click menu, returning what to evaluate and which symbol to insert
+    fn context_menu(
+        &self,
+        ui: &mut Ui,
+        selected: bool,
+        read_only: bool,
+    ) -> (Option<EvalScope>, Option<Edit>) {
+        let mut eval = None;
+        if ui
+            .add_enabled(selected, egui::Button::new("Evaluate selection"))
+            .clicked()
+        {
+            eval = Some(EvalScope::Expression);
+            ui.close_menu();
+        }
+        let paragraph = egui::Button::new("Evaluate paragraph")
+            .shortcut_text(ui.ctx().format_shortcut(&PARAGRAPH_SHORTCUT));
+        if ui.add(paragraph).clicked() {
+            eval = Some(EvalScope::Paragraph);
+            ui.close_menu();
+        }
+        if ui
+            .add_enabled(
+                self.last_result.is_some(),
+                egui::Button::new("Copy last result"),
+            )
+            .clicked()
+        {
+            ui.ctx()
+                .copy_text(self.last_result.clone().unwrap_or_default());
+            ui.close_menu();
+        }
+        let mut edit = None;
+        ui.add_enabled_ui(!read_only, |ui| {
+            ui.menu_button("Insert symbol", |ui| {
+                for (shortcut, x) in &SYMBOL_SHORTCUTS {
+                    let button = egui::Button::new(x.to_string())
+                        .shortcut_text(ui.ctx().format_shortcut(shortcut));
+                    if ui.add(button).clicked() {
+                        edit = Some(Edit::Insert(*x));
+                        ui.close_menu();
+                    }
+                }
+            });
+            ui.add_enabled_ui(selected, |ui| {
+                for (running, label) in [(false, "Total lines"), (true, "Total with running sums")]
+                {
+                    if ui.button(label).clicked() {
+                        edit = Some(Edit::Total { running });
+                        ui.close_menu();
+                    }
+                }
+                if ui.button("Total tab-separated grid").clicked() {
+                    edit = Some(Edit::TotalGrid);
+                    ui.close_menu();
+                }
+                if ui.button("Percent of total").clicked() {
+                    edit = Some(Edit::PercentOfTotal);
+                    ui.close_menu();
+                }
+                for (sequence, label) in [
+                    (Sequence::Differences, "Differences (diff)"),
+                    (Sequence::RunningSums, "Running sums (cumsum)"),
+                ] {
+                    if ui.button(label).clicked() {
+                        edit = Some(Edit::Sequence(sequence));
+                        ui.close_menu();
+                    }
+                }
+            });
+        });
+        (eval, edit)
+    }
+
+    /// Show the buttons above the note, returning whether it is read-only
+    fn toolbar(&mut self, ui: &mut Ui) -> bool {
+        ui.horizontal(|ui| {
+            self.settings_open ^= ui.button("Settings").clicked();
+            self.help_open ^= ui.button("Help").clicked();
+            let mut read_only = self.read_only.contains(&self.active);
+            let embedded = ui
+                .add_enabled(
+                    !read_only && !self.calculator_only,
+                    egui::Button::new("Evaluate {{ }}"),
+                )
+                .on_hover_text(
+                    "Fill in the result of every `{{ expression }}` in the note, and the note's \
+                     title and the current date for `{{title}}` and `{{date}}`",
+                );
+            if embedded.clicked() {
+                let mut env = self.environment();
+                let note = &mut self.notes_list[self.active];
+                let before = note.clone();
+                let expanded = expand_placeholders(note, SystemTime::now());
+                *note = evaluate_embedded(&expanded, &mut env, &self.result_template);
+                self.history.record(
+                    Snapshot {
+                        text: before,
+                        selection: self.selection,
+                    },
+                    Snapshot {
+                        text: note.clone(),
+                        selection: self.selection,
+                    },
+                );
+                self.variables.insert(self.active, env.variables);
+            }
+            ui.checkbox(&mut read_only, "Read-only")
+                .on_hover_text("Evaluation results go to a scratch area below the note");
+            if !self.warnings.is_empty() {
+                let warnings = self.warnings.iter().map(ToString::to_string);
+                ui.colored_label(ui.visuals().warn_fg_color, "⚠")
+                    .on_hover_text(format!(
+                        "The last result may be misleading: {}",
+                        warnings.collect::<Vec<_>>().join(", ")
+                    ));
+            }
+            if let Some((_, message)) = &self.parse_error {
+                ui.colored_label(ui.visuals().error_fg_color, message);
+            }
+            if let Some(result) = self.shown_result.as_ref().filter(|_| self.calculator_only) {
+                ui.separator();
+                ui.label(result);
+            }
+            if read_only {
+                self.read_only.insert(self.active);
+            } else {
+                self.read_only.remove(&self.active);
+            }
+            read_only
+        })
+        .inner
+    }
+
+    /// List the keyboard shortcuts
+    fn help_window(&mut self, ctx: &egui::Context) {
+        egui::Window::new("Help")
+            .open(&mut self.help_open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("shortcuts").show(ui, |ui| {
+                    let shift = self.shift_enter_evaluates.then_some(Modifiers::SHIFT);
+                    for modifiers in iter::once(Modifiers::CTRL).chain(shift) {
+                        ui.label(
+                            ctx.format_shortcut(&KeyboardShortcut::new(modifiers, Key::Enter)),
+                        );
+                        ui.label(format!(
+                            "Evaluate the selection, or the expression at the cursor; \
+                             `{ANS}` holds the last result"
+                        ));
+                        ui.end_row();
+                    }
+                    ui.label("(");
+                    ui.label(
+                        "With text selected, wrap it in parentheses; type a function name next \
+                         to apply the function to it",
+                    );
+                    ui.end_row();
+                    ui.label(ctx.format_shortcut(&PARAGRAPH_SHORTCUT));
+                    ui.label(
+                        "Evaluate the paragraph at the cursor: each line in turn, inserting the \
+                         last one's result",
+                    );
+                    ui.end_row();
+                    ui.label(ctx.format_shortcut(&DUPLICATE_SHORTCUT));
+                    ui.label("Duplicate the current line below it");
+                    ui.end_row();
+                    ui.label(ctx.format_shortcut(&NEXT_RESULT_SHORTCUT));
+                    ui.label(
+                        "Move to the next result in the note, going back to the first after the \
+                         last",
+                    );
+                    ui.end_row();
+                    ui.label(ctx.format_shortcut(&REEVALUATE_NEXT_SHORTCUT));
+                    ui.label("Move to the next result and evaluate its expression again");
+                    ui.end_row();
+                    ui.label(ctx.format_shortcut(&DATE_SHORTCUT));
+                    ui.label(
+                        "Insert the current local date and time; now() in an expression gives \
+                         it in seconds since 1970",
+                    );
+                    ui.end_row();
+                    ui.label(ctx.format_shortcut(&QUICK_SHORTCUT));
+                    ui.label(
+                        "Open or close the quick calculation bar, which leaves the note untouched",
+                    );
+                    ui.end_row();
+                    ui.label(ctx.format_shortcut(&UNDO_SHORTCUT));
+                    ui.label(
+                        "Undo the last inserted result or other change made for you, if nothing \
+                         has been typed since",
+                    );
+                    ui.end_row();
+                    ui.label(ctx.format_shortcut(&REDO_SHORTCUT));
+                    ui.label("Redo the last undone change");
+                    ui.end_row();
+                    for (shortcut, symbol) in &SYMBOL_SHORTCUTS {
+                        ui.label(ctx.format_shortcut(shortcut));
+                        ui.label(format!("Insert {symbol}"));
+                        ui.end_row();
+                    }
+                });
+            });
+    }
+
+    /// Show the results of evaluating read-only notes, if there are any
+    fn scratch_panel(&mut self, ctx: &egui::Context) {
+        if self.scratch.is_empty() {
+            return;
+        }
+        egui::TopBottomPanel::bottom("scratch").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Scratch");
+                if ui.button("Clear").clicked() {
+                    self.scratch.clear();
+                }
+            });
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.add(
+                    egui::TextEdit::multiline(&mut self.scratch.as_str())
+                        .desired_width(f32::INFINITY),
+                );
+            });
+        });
+    }
+
+    /// Show the quick calculation bar, if open: a line evaluated as it is typed, which can use the
+    /// note's variables but never changes the note or them
+    fn quick_bar(&mut self, ctx: &egui::Context) {
+        let toggled = ctx.input_mut(|x| x.consume_shortcut(&QUICK_SHORTCUT));
+        self.quick_open ^= toggled;
+        if !self.quick_open {
+            return;
+        }
+        egui::TopBottomPanel::bottom("quick").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Quick:");
+                let input = ui.add(
+                    egui::TextEdit::singleline(&mut self.quick_input)
+                        .id(egui::Id::new(QUICK_ID))
+                        .hint_text("expression"),
+                );
+                if toggled {
+                    input.request_focus();
+                }
+                // Variables may have changed while the bar was closed
+                if input.changed() || toggled {
+                    self.quick_result = if self.quick_input.trim().is_empty() {
+                        String::new()
+                    } else {
+                        // Assignments go to this copy of the note's variables, which is dropped
+                        let mut env = self.environment();
+                        format_result(execute(&self.quick_input, &mut env), &env)
+                    };
+                }
+                ui.label(&self.quick_result);
+                let evaluated = !self.quick_result.is_empty();
+                if ui
+                    .add_enabled(evaluated, egui::Button::new("Copy"))
+                    .clicked()
+                {
+                    ui.output_mut(|x| x.copied_text.clone_from(&self.quick_result));
+                }
+                let add = ui
+                    .add_enabled(evaluated, egui::Button::new("Add to scratch"))
+                    .on_hover_text("Keep the calculation in the scratch area below the note");
+                if add.clicked() {
+                    self.scratch.push_str(&self.quick_input);
+                    self.scratch
+                        .push_str(&render_result(&self.result_template, &self.quick_result));
+                    self.scratch.push('\n');
+                }
+            });
+        });
+    }
+
+    /// Offer explicit parentheses for the last evaluated expression if its grouping is misleading
+    fn hint_window(&mut self, ctx: &egui::Context) {
+        if let Some(hint) = &self.hint {
+            let (mut apply, mut dismissed) = (false, false);
+            egui::Window::new("Precedence")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(format!("{} was read as {}", hint.text, hint.parenthesized));
+                    ui.horizontal(|ui| {
+                        apply = ui.button("Insert parentheses").clicked();
+                        dismissed = ui.button("Dismiss").clicked();
+                    });
+                });
+            if apply {
+                let note = &mut self.notes_list[self.active];
+                // Leave the note alone if the expression was edited in the meantime
+                if note.char_range(hint.range.clone()) == hint.text {
+                    let before = note.clone();
+                    note.delete_char_range(hint.range.clone());
+                    note.insert_text(&hint.parenthesized, hint.range.start);
+                    self.history.record(
+                        Snapshot {
+                            text: before,
+                            selection: self.selection,
+                        },
+                        Snapshot {
+                            text: note.clone(),
+                            selection: self.selection,
+                        },
+                    );
+                }
+            }
+            if apply || dismissed {
+                self.hint = None;
+            }
+        }
+    }
+
+    /// Show how the last evaluated expression was grouped, if asked to in Settings
+    fn tree_window(&mut self, ctx: &egui::Context) {
+        if !self.tree_open {
+            return;
+        }
+        let env = self.parse_environment();
+        egui::Window::new("Parse tree")
+            .open(&mut self.tree_open)
+            .show(ctx, |ui| match &self.last_expression {
+                Some(text) => {
+                    ui.label(text);
+                    match expr::parse_tree(text, &env) {
+                        Ok(tree) => {
+                            show_tree(ui, &tree, egui::Id::new("parse tree"));
+                            show_simplified(ui, text, &env);
+                        }
+                        Err(x) => {
+                            ui.colored_label(ui.visuals().error_fg_color, x.to_string());
+                        }
+                    }
+                }
+                None => {
+                    ui.label("Evaluate an expression to see how it was read.");
+                }
+            });
+    }
+
+    fn settings_ui(&mut self, ui: &mut Ui) {
+        ui.checkbox(&mut self.fixed_width, "Enable monospace / fixed-width font");
+        self.font_ui(ui);
+        self.conventions_ui(ui);
+        ui.horizontal(|ui| {
+            let mut rounded = self.precision.is_some();
+            ui.checkbox(&mut rounded, "Round results to");
+            let mut places = self.precision.unwrap_or(2);
+            ui.add_enabled(
+                rounded,
+                egui::DragValue::new(&mut places).clamp_range(0..=30),
+            );
+            ui.label("decimal places");
+            self.precision = rounded.then_some(places);
+        });
+        ui.add_enabled(
+            self.precision.is_none(),
+            egui::Checkbox::new(
+                &mut self.extended_digits,
+                format!(
+                    "Show results with all {} significant digits",
+                    expr::EXTENDED_DIGITS
+                ),
+            ),
+        )
+        .on_hover_text(
+            "Results are always worked out to about 34 digits, but are otherwise shown with only \
+             the 17 or so of a standard 64-bit float",
+        );
+        ui.horizontal(|ui| {
+            ui.label("Scientific notation from 1e");
+            ui.add(egui::DragValue::new(&mut self.scientific_above).clamp_range(1..=300));
+            ui.label("and below 1e");
+            ui.add(egui::DragValue::new(&mut self.scientific_below).clamp_range(-300..=0));
+        });
+        ui.checkbox(
+            &mut self.programmer_mode,
+            "Programmer mode: `^` is bitwise XOR (use `**` for powers)",
+        );
+        ui.checkbox(
+            &mut self.ieee_division,
+            "Allow division by zero (gives `inf` or `NaN`)",
+        );
+        ui.checkbox(
+            &mut self.shift_enter_evaluates,
+            "Shift+Enter evaluates, like Ctrl+Enter, instead of starting a new line",
+        );
+        ui.checkbox(
+            &mut self.decimal_comma_paste,
+            "Read `,` as a decimal point in pasted numbers like `3,14`",
+        )
+        .on_hover_text(
+            "Only when the pasted text has no `.` and no comma in it looks like a thousands \
+             separator, as in `1,234`, or is followed by a space, as in `max(1, 2)`. Arguments \
+             written without spaces, as in `max(1,2)`, are changed too",
+        );
+        ui.checkbox(
+            &mut self.tree_open,
+            "Show how the last evaluated expression was read, as a tree",
+        );
+        ui.checkbox(
+            &mut self.lenient_trailing,
+            "Ignore text after an expression, like the unit in `2 + 3 seconds`",
+        );
+        ui.checkbox(
+            &mut self.strict_identifiers,
+            "Warn when a variable right before `(` is multiplied, like `sln(2)` meant as `sin(2)`",
+        );
+        self.results_ui(ui);
+        ui.horizontal(|ui| {
+            ui.label("Expressions at the cursor stop at:");
+            ui.add(egui::TextEdit::singleline(&mut self.terminators).desired_width(80.0))
+                .on_hover_text(format!(
+                    "Each character, as well as a line break, ends the expression around the \
+                     cursor, like `;` or `,`. Default: `{DEFAULT_TERMINATORS}`"
+                ));
+        });
+        self.limits_ui(ui);
+    }
+
+    /// Show the choices of how results are inserted into the notes and shown there
+    fn results_ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Insert results as:");
+            ui.add(egui::TextEdit::singleline(&mut self.template_draft).lock_focus(true))
+                .on_hover_text(format!(
+                    "`{{}}` stands for the result. Default: `{DEFAULT_RESULT_TEMPLATE}`"
+                ));
+            if is_valid_template(&self.template_draft) {
+                self.result_template.clone_from(&self.template_draft);
+            } else {
+                ui.colored_label(
+                    ui.visuals().error_fg_color,
+                    "Must contain `{}` exactly once and no other braces",
+                );
+            }
+        });
+        ui.checkbox(
+            &mut self.result_on_new_line,
+            "Insert results on a new line below the expression",
+        );
+        ui.checkbox(
+            &mut self.calculator_only,
+            "Calculator only: show results above the note instead of inserting them",
+        );
+        ui.checkbox(
+            &mut self.recompute_on_open,
+            "Recompute results when the notes are opened",
+        )
+        .on_hover_text(
+            "Replaces results that are out of date, for example after editing the notes \
+             elsewhere. Off, the notes open exactly as they were saved",
+        );
+        ui.checkbox(&mut self.sign_colors, "Color results by their sign")
+            .on_hover_text(
+                "Positive results are green, negative ones red, and zero dimmed. Errors and \
+                 results with a `±` keep the note's color",
+            );
+    }
+
+    /// Show the choices of angle unit and of what `log` means, which change what expressions mean
+    fn conventions_ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Angles:");
+            ui.radio_value(&mut self.angle_mode, AngleMode::Radians, "Radians");
+            ui.radio_value(&mut self.angle_mode, AngleMode::Degrees, "Degrees");
+        });
+        ui.horizontal(|ui| {
+            ui.label("`log` means:");
+            ui.radio_value(&mut self.log_base, LogBase::Ten, "Base 10");
+            ui.radio_value(&mut self.log_base, LogBase::Natural, "Natural (base e)");
+        })
+        .response
+        .on_hover_text(
+            "Changes the result of every `log` in every note. `log10` and `ln` always mean base \
+             10 and base e",
+        );
+    }
+
+    /// Show the choice of editor font
+    fn font_ui(&mut self, ui: &mut Ui) {
+        let mut chosen = None;
+        ui.horizontal(|ui| {
+            ui.label("Editor font:");
+            let selected = BUNDLED_FONTS
+                .iter()
+                .find(|x| x.0 == self.font)
+                .map_or(self.font.as_str(), |x| x.1);
+            egui::ComboBox::from_id_source("font")
+                .selected_text(if selected.is_empty() {
+                    "Default"
+                } else {
+                    selected
+                })
+                .show_ui(ui, |ui| {
+                    if ui
+                        .selectable_label(self.font.is_empty(), "Default")
+                        .clicked()
+                    {
+                        chosen = Some(String::new());
+                    }
+                    for (name, description) in BUNDLED_FONTS {
+                        if ui
+                            .selectable_label(self.font == name, description)
+                            .clicked()
+                        {
+                            chosen = Some(name.to_owned());
+                        }
+                    }
+                });
+        });
+        ui.horizontal(|ui| {
+            ui.label("Font file:");
+            ui.text_edit_singleline(&mut self.font_path_draft)
+                .on_hover_text(
+                    "Path to a TrueType or OpenType font, such as one that is installed",
+                );
+            if ui.button("Load").clicked() {
+                chosen = Some(self.font_path_draft.trim().to_owned());
+            }
+        });
+        if let Some(font) = chosen.filter(|x| *x != self.font) {
+            self.use_font(ui.ctx(), font);
+        }
+    }
+
+    /// Show the settings that bound how much work `sum`, `prod`, and `integ` do
+    fn limits_ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Most terms in a sum or product:");
+            ui.add(
+                egui::DragValue::new(&mut self.summation_limit).clamp_range(expr::SUMMATION_LIMITS),
+            )
+            .on_hover_text(format!(
+                "Larger sums are rejected rather than left to run. Default: {}",
+                expr::DEFAULT_SUMMATION_LIMIT
+            ));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Integration subdivisions:");
+            ui.add(
+                egui::DragValue::new(&mut self.integration_steps)
+                    .clamp_range(expr::INTEGRATION_STEPS),
+            )
+            .on_hover_text(format!(
+                "More is more accurate but proportionally slower. Default: {}",
+                expr::DEFAULT_INTEGRATION_STEPS
+            ));
+        });
+    }
+
+    /// The characters that end the expression at the cursor, which always include line breaks
+    fn terminators(&self) -> Vec<char> {
+        self.terminators
+            .chars()
+            .filter(|x| !x.is_whitespace())
+            .chain(['\n'])
+            .collect()
+    }
+
+    /// The template results are inserted into the note with, which starts a new line if results go
+    /// below their expressions
+    fn note_template(&self) -> String {
+        if self.result_on_new_line {
+            format!("\n{}", self.result_template.trim_start())
+        } else {
+            self.result_template.clone()
+        }
+    }
+
+    /// The environment expressions in the active note are evaluated in, with the note's cells,
+    /// which gives up after [`EVAL_TIMEOUT`] so a runaway expression cannot freeze the editor.
+    ///
+    /// Working out the cells evaluates every cell in the note, so this is made when something is
+    /// evaluated rather than every frame.
+    fn environment(&self) -> Environment {
+        let mut env = self.parse_environment();
+        env.cells = note_cells_values(&self.notes_list[self.active], &self.result_template, &env);
+        env
+    }
+
+    /// The environment of [`Self::environment`] without the note's cells, which is all that reading
+    /// an expression needs
+    fn parse_environment(&self) -> Environment {
+        Environment {
+            variables: self
+                .variables
+                .get(&self.active)
+                .cloned()
+                .unwrap_or_default(),
+            angle_mode: self.angle_mode,
+            log_base: self.log_base,
+            precision: self.precision,
+            summation_limit: self.summation_limit,
+            integration_steps: self.integration_steps,
+            programmer_mode: self.programmer_mode,
+            ieee_division: self.ieee_division,
+            lenient_trailing: self.lenient_trailing,
+            strict_identifiers: self.strict_identifiers,
+            scientific_above: self.scientific_above,
+            scientific_below: self.scientific_below,
+            extended_digits: self.extended_digits,
+            deadline: Some(Instant::now() + EVAL_TIMEOUT),
+            ..Environment::default()
+        }
+    }
+}
+
+/// Whether `text` evaluates without error, either on its own, for both signs of a `±`, or as a
+/// conversion to another base
+fn evaluates(text: &str, env: &Environment) -> bool {
+    execute(text, &mut env.clone()).is_ok()
+        || expr::eval_plus_minus(text, env).is_some_and(|x| x.is_ok())
+        || expr::base(text, env).is_some_and(|x| x.is_ok())
+}
+
+/// Character range of the paragraph around `cursor`: the lines around it up to blank lines, without
+/// the line break ending the last. It is empty if the cursor is on a blank line.
+fn paragraph_range(note: &str, cursor: usize) -> Range<usize> {
+    let blank = |line: &str| line.trim().is_empty();
+    let mut start = 0;
+    let mut range = 0..0;
+    for line in note.split('\n') {
+        let end = start + line.chars().count();
+        if blank(line) {
+            if start > cursor {
+                break;
+            }
+            range = end + 1..end + 1;
+        } else {
+            range.end = end;
+        }
+        start = end + 1;
+    }
+    if range.start > cursor {
+        cursor..cursor
+    } else {
+        range
+    }
+}
+
+/// Character range of the expression to evaluate for a cursor with the given ends.
+///
+/// A selection is evaluated as-is, which may span several lines, except for trailing whitespace so
+/// that the result goes right after the expression rather than on the line after a selection of
+/// whole lines. A bare cursor evaluates back to the previous of the `terminators`, such as `:`,
+/// `=`, or newline, plus any assignments just before it (`a = b = 5`), and forward to the next of
+/// them other than an assignment's `=`, so that the cursor may be anywhere in the expression and a
+/// result already inserted after it is left out. Leading and trailing words that keep the text from
+/// being an expression are dropped, so that math written in the middle of a sentence, like
+/// `the total is 2+2 apples`, is picked out on its own, as is indentation.
+fn expression_range(
+    note: &str,
+    primary: usize,
+    secondary: usize,
+    terminators: &[char],
+    is_expression: impl Fn(&str) -> bool,
+) -> Range<usize> {
+    let end = primary.max(secondary);
+    if primary != secondary {
+        let start = primary.min(secondary);
+        let selected = note.char_range(start..end);
+        return start..start + selected.trim_end().chars().count();
+    }
+    let cursor = note.byte_index_from_char_index(end);
+    let mut from = cursor;
+    let line_end = loop {
+        match note[from..].find(terminators).map(|x| from + x) {
+            Some(x) if note[x..].starts_with('=') && assignment_start(&note[..=x]).is_some() => {
+                from = x + 1;
+            }
+            Some(x) => break x,
+            None => break note.len(),
+        }
+    };
+    let stop = cursor + note[cursor..line_end].trim_end().len();
+    let before = &note[..cursor];
+    let mut start = before.rfind(terminators).map_or(0, |x| {
+        x + before[x..].chars().next().map_or(0, char::len_utf8)
+    });
+    while let Some(x) = assignment_start(&before[..start]) {
+        start = x;
+    }
+    let starts: Vec<_> = iter::once(start)
+        .chain(
+            note[start..cursor]
+                .char_indices()
+                .map(|(i, c)| (start + i, c))
+                .filter(|&(i, c)| !c.is_whitespace() && note[..i].ends_with(char::is_whitespace))
+                .map(|(i, _)| i),
+        )
+        .collect();
+    if stop - start > expr::MAX_EXPRESSION_LEN {
+        // Too long to be an expression anyway, and too long to search for one in, as each try
+        // lexes it again
+        return note[..start].chars().count()..note[..stop].chars().count();
+    }
+    let ends = iter::once(stop).chain(
+        note[cursor..stop]
+            .char_indices()
+            .rev()
+            .map(|(i, c)| (cursor + i, c))
+            .filter(|&(i, c)| c.is_whitespace() && !note[..i].ends_with(char::is_whitespace))
+            .map(|(i, _)| i),
+    );
+    let (start, stop) = ends
+        .flat_map(|end| starts.iter().map(move |&start| (start, end)))
+        .find(|&(start, end)| is_expression(&note[start..end]))
+        .unwrap_or((start, stop));
+    // Leave out indentation, so that only the expression is highlighted
+    let start = stop - note[start..stop].trim_start().len();
+    note[..start].chars().count()..note[..stop].chars().count()
+}
+
+/// If `text` ends with an assignment such as `x =` or `θ +=`, the byte index of the variable name,
+/// whose letters may each be several bytes long
+fn assignment_start(text: &str) -> Option<usize> {
+    let text = text.trim_end().strip_suffix('=')?;
+    let text = text
+        .strip_suffix(['+', '-', '*', '/'])
+        .unwrap_or(text)
+        .trim_end();
+    let start = text
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| c.is_alphanumeric())
+        .last()?
+        .0;
+    text[start..]
+        .starts_with(char::is_alphabetic)
+        .then_some(start)
+}
+
+/// The title of `note`: its first line with text, without the `#` of a heading
+fn note_title(note: &str) -> &str {
+    note.lines()
+        .map(|x| x.trim().trim_start_matches('#').trim_start())
+        .find(|x| !x.is_empty())
+        .unwrap_or_default()
+}
+
+/// Replace each `{{title}}` in `note` with its [`note_title`] and each `{{date}}` with `time` as
+/// [`format_date`] writes it. Other pairs are left as they are.
+fn expand_placeholders(note: &str, time: SystemTime) -> String {
+    let title = note_title(note);
+    let mut result = String::new();
+    let mut rest = note;
+    while let Some((open, close)) = rest
+        .find("{{")
+        .and_then(|open| Some((open, open + rest[open..].find("}}")?)))
+    {
+        result.push_str(&rest[..open]);
+        match rest[open + 2..close].trim() {
+            "title" => result.push_str(title),
+            "date" => result.push_str(&format_date(time)),
+            _ => result.push_str(&rest[open..close + 2]),
+        }
+        rest = &rest[close + 2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Evaluate each expression between `{{` and `}}` in `note`, in order, inserting its result after
+/// it with `template`, so that `{{ 2+2 }}` becomes `{{ 2+2 = 4 }}`.
+///
+/// Only the innermost of nested pairs is evaluated. Contents that fail to evaluate are left as they
+/// are, which includes pairs that already hold a result, so evaluating again changes nothing.
+fn evaluate_embedded(note: &str, env: &mut Environment, template: &str) -> String {
+    let mut result = String::new();
+    let mut rest = note;
+    while let Some(close) = rest.find("}}") {
+        let Some(open) = rest[..close].rfind("{{") else {
+            result.push_str(&rest[..close + 2]);
+            rest = &rest[close + 2..];
+            continue;
+        };
+        let inner = &rest[open + 2..close];
+        result.push_str(&rest[..open + 2]);
+        if let Ok(x) = execute(inner, env) {
+            let expression = inner.trim_end();
+            result.push_str(expression);
+            result.push_str(&render_result(template, &format_result(Ok(x), env)));
+            result.push_str(&inner[expression.len()..]);
+        } else {
+            result.push_str(inner);
+        }
+        result.push_str("}}");
+        rest = &rest[close + 2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Evaluate each line of `note` in order, as [`expr::evaluate_document`] does, replacing each
+/// result inserted with `template` by the result its expression gives now.
+///
+/// Returns the note and how many lines had their result replaced. Results on a line of their own
+/// are left as they are, as are those after text that no longer reads as an expression.
+fn recompute_results(
+    note: &str,
+    env: &mut Environment,
+    template: &str,
+    terminators: &[char],
+) -> (String, usize) {
+    let mut changed = 0;
+    let mut lines = Vec::new();
+    for line in note.split('\n') {
+        env.deadline = Some(Instant::now() + EVAL_TIMEOUT);
+        let Some(start) = trailing_result(template, line) else {
+            let _ = execute(line, env);
+            lines.push(line.to_owned());
+            continue;
+        };
+        let before = &line[..start];
+        let expression = line_expression(line, template, terminators, |x| evaluates(x, env));
+        let result = if let Some(result) = expr::eval_plus_minus(expression, env) {
+            result.map(|(plus, minus)| format_plus_minus(plus, minus, env))
+        } else {
+            let result = execute(expression, env);
+            if let Ok(x) = result {
+                env.variables.insert(ANS.to_owned(), x);
+            }
+            result.map(|x| format_result(Ok(x), env))
+        };
+        let result = match result {
+            Err(x) if x.is_unreadable() => None,
+            result => Some(render_result(
+                template,
+                &result.unwrap_or_else(|x| x.to_string()),
+            )),
+        };
+        match result.filter(|x| *x != line[start..]) {
+            Some(result) => {
+                changed += 1;
+                lines.push(format!("{before}{result}"));
+            }
+            None => lines.push(line.to_owned()),
+        }
+    }
+    (lines.join("\n"), changed)
+}
+
+/// The cells defined in `note`, like `A1: 5`, as pairs of a name and a formula without any result
+/// inserted after it with `template`
+fn note_cells<'a>(note: &'a str, template: &str) -> Vec<(&'a str, &'a str)> {
+    note.lines()
+        .filter_map(|line| {
+            let end = trailing_result(template, line).unwrap_or(line.len());
+            expr::cell_definition(&line[..end])
         })
+        .collect()
+}
+
+/// The values of the cells defined in `note`, evaluated in `env`
+fn note_cells_values(
+    note: &str,
+    template: &str,
+    env: &Environment,
+) -> HashMap<String, expr::Result<Value>> {
+    let cells = note_cells(note, template);
+    if cells.is_empty() {
+        HashMap::new()
+    } else {
+        expr::evaluate_cells(&cells, env)
+    }
+}
+
+/// Hashes of the lines of `note` with a result inserted with `template` that depend on any of
+/// `cells`, directly or through other cells
+fn dependent_lines(
+    note: &str,
+    template: &str,
+    terminators: &[char],
+    mut cells: Vec<String>,
+) -> Vec<u64> {
+    let definitions = note_cells(note, template);
+    let depends = |formula: &str, cells: &[String]| {
+        expr::cell_references(formula)
+            .iter()
+            .any(|x| cells.contains(x))
+    };
+    loop {
+        let dependents: Vec<String> = definitions
+            .iter()
+            .filter(|&&(name, formula)| {
+                !cells.iter().any(|x| x == name) && depends(formula, &cells)
+            })
+            .map(|&(name, _)| name.to_owned())
+            .collect();
+        if dependents.is_empty() {
+            break;
+        }
+        cells.extend(dependents);
     }
+    note.lines()
+        .filter(|line| {
+            trailing_result(template, line).is_some_and(|_| {
+                // Whatever could be part of the expression counts, as it is not evaluated here
+                let expression = line_expression(line, template, terminators, |_| true);
+                depends(expression, &cells)
+            })
+        })
+        .map(line_hash)
+        .collect()
+}
+
+/// Replace the editor's selection with `text`, leaving the cursor after it
+fn insert_text(note: &mut String, output: &mut TextEditOutput, text: &str) {
+    if let Some(cursor) = output.cursor_range {
+        let range = cursor.as_sorted_char_range();
+        note.delete_char_range(range.clone());
+        let end = range.start + note.insert_text(text, range.start);
+        set_selection(output, end..end);
+    }
+}
+
+/// `time` as its local date and time to the minute, with the offset of the time zone from UTC, like
+/// `2026-10-15 16:03 +02:00`
+fn format_date(time: SystemTime) -> String {
+    format_date_at(time, local_offset(time))
+}
+
+/// `time` as the date and time to the minute `offset` seconds east of UTC, as [`format_date`]
+/// writes it
+fn format_date_at(time: SystemTime, offset: i64) -> String {
+    let secs = time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |x| {
+        i64::try_from(x.as_secs()).unwrap_or(i64::MAX - 86_400)
+    }) + offset;
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since 1970-01-01, counting in 400-year eras of 146097 days that start
+    // on the 1st of March, so that leap days fall at the end of each year
+    let days = days + 719_468;
+    let (era, day_of_era) = (days.div_euclid(146_097), days.rem_euclid(146_097));
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + i64::from(month <= 2);
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} {sign}{:02}:{:02}",
+        secs / 3600,
+        secs % 3600 / 60,
+        offset.abs() / 3600,
+        offset.abs() % 3600 / 60
+    )
+}
+
+/// Seconds east of UTC of the local time zone at `time`, as the C library knows it
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn local_offset(time: SystemTime) -> i64 {
+    let Some(secs) = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .and_then(|x| libc::time_t::try_from(x.as_secs()).ok())
+    else {
+        return 0;
+    };
+    // SAFETY: `tm` is plain old data, for which all zeroes is a valid value, and `localtime_r`
+    // only writes through the pointers it is given, which are valid for the call
+    unsafe {
+        let mut tm: libc::tm = core::mem::zeroed();
+        if libc::localtime_r(&raw const secs, &raw mut tm).is_null() {
+            0
+        } else {
+            tm.tm_gmtoff
+        }
+    }
+}
+
+/// Seconds east of UTC of the local time zone, which is taken to be UTC where there is no
+/// `localtime_r` to ask
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn local_offset(_time: SystemTime) -> i64 {
+    0
+}
+
+/// Move the cursor to the start of the first result in `note` past it, laid out by `template`, or
+/// if there is none or no cursor, to the first result in the note
+fn next_result(note: &str, output: &mut TextEditOutput, template: &str) {
+    let at = output
+        .cursor_range
+        .map(|x| note.byte_index_from_char_index(x.as_sorted_char_range().end));
+    let mut line_start = 0;
+    let mut results = Vec::new();
+    for line in note.split_inclusive('\n') {
+        let content = line.strip_suffix('\n').unwrap_or(line);
+        results.extend(trailing_result(template, content).map(|x| line_start + x));
+        line_start += line.len();
+    }
+    if let Some(&next) = results
+        .iter()
+        .find(|&&x| at.is_some_and(|at| x > at))
+        .or_else(|| results.first())
+    {
+        let index = note[..next].chars().count();
+        set_selection(output, index..index);
+        // Evaluating right after moving reads the cursor from here
+        output.cursor_range = Some(CursorRange::one(
+            output.galley.from_ccursor(egui::text::CCursor::new(index)),
+        ));
+    }
+}
+
+/// Copy the lines the selection touches to just below them, moving the selection onto the copy
+fn duplicate_lines(note: &mut String, output: &mut TextEditOutput) {
+    if let Some(cursor) = output.cursor_range {
+        let range = cursor.as_sorted_char_range();
+        let (start, end) = (
+            note.byte_index_from_char_index(range.start),
+            note.byte_index_from_char_index(range.end),
+        );
+        let line_start = note[..start].rfind('\n').map_or(0, |x| x + 1);
+        let line_end = note[end..].find('\n').map_or(note.len(), |x| end + x);
+        let copy = format!("\n{}", &note[line_start..line_end]);
+        note.insert_str(line_end, &copy);
+        let shift = copy.chars().count();
+        let (primary, secondary) = (cursor.primary.ccursor.index, cursor.secondary.ccursor.index);
+        set_selection(output, secondary + shift..primary + shift);
+    }
+}
+
+/// Separates a line from its running total as inserted by [`total_lines`]
+const RUNNING_TOTAL: &str = " → ";
+
+/// Add up the lines the selection touches, and put `Total = ` their sum on a line below them,
+/// selecting it.
+///
+/// Blank lines are skipped, as are lines that are not numbers or expressions, which the total
+/// line counts, as in `Total = 15.5 (1 line skipped)`. With `running`, each line that was added
+/// also gets [`RUNNING_TOTAL`] and the sum so far, replacing any it had.
+fn total_lines(note: &mut String, output: &mut TextEditOutput, env: &Environment, running: bool) {
+    let Some(range) = selected_lines(note, output) else {
+        return;
+    };
+    let (lines, line_start) = (&note[range.clone()], range.start);
+    let (mut total, mut skipped) = (0.0, 0);
+    let mut replacement = String::new();
+    for line in lines.lines() {
+        let line = line.split_once(RUNNING_TOTAL).map_or(line, |x| x.0);
+        replacement.push_str(line);
+        if !line.trim().is_empty() {
+            if let Ok(x) = expr::eval_with(line, env) {
+                total += x;
+                if running {
+                    replacement.push_str(RUNNING_TOTAL);
+                    replacement.push_str(&format_result(Ok(total), env));
+                }
+            } else {
+                skipped += 1;
+            }
+        }
+        replacement.push('\n');
+    }
+    let skipped = match skipped {
+        0 => String::new(),
+        1 => " (1 line skipped)".to_owned(),
+        _ => format!(" ({skipped} lines skipped)"),
+    };
+    let total_line = format!("Total = {}{skipped}", format_result(Ok(total), env));
+    replacement.push_str(&total_line);
+    let len = lines.len();
+    note.replace_range(line_start..line_start + len, &replacement);
+    let end = note[..line_start + replacement.len()].chars().count();
+    set_selection(output, end - total_line.chars().count()..end);
+}
+
+/// Opens the share of the total that [`percent_of_total`] puts after a line, which ends with `)`
+const PERCENT_OF_TOTAL: &str = " (";
+
+/// Follow each line the selection touches with its share of their total, as in `20 (40%)`,
+/// selecting the lines.
+///
+/// Lines that are blank or not numbers or expressions are skipped and left as they are. Shares
+/// from an earlier run are replaced, so a column can be worked out again after it is edited. If
+/// the lines add up to zero, each gets [`expr::Error::DivByZero`] instead of a share.
+#[allow(clippy::float_cmp)]
+fn percent_of_total(note: &mut String, output: &mut TextEditOutput, env: &Environment) {
+    let Some(range) = selected_lines(note, output) else {
+        return;
+    };
+    let (lines, line_start) = (&note[range.clone()], range.start);
+    let values: Vec<(&str, Option<Value>)> = lines
+        .lines()
+        .map(|line| {
+            let line = line
+                .strip_suffix(')')
+                .and_then(|x| x.rsplit_once(PERCENT_OF_TOTAL))
+                .filter(|x| x.1.ends_with('%') || x.1 == expr::Error::DivByZero.to_string())
+                .map_or(line, |x| x.0);
+            let value = (!line.trim().is_empty())
+                .then(|| expr::eval_with(line, env).ok())
+                .flatten();
+            (line, value)
+        })
+        .collect();
+    let total: Value = values.iter().filter_map(|x| x.1).sum();
+    let mut replacement = String::new();
+    for (line, value) in values {
+        replacement.push_str(line);
+        if let Some(x) = value {
+            replacement.push_str(PERCENT_OF_TOTAL);
+            if total == 0.0 {
+                replacement.push_str(&format_result(Err(expr::Error::DivByZero), env));
+                replacement.push(')');
+            } else {
+                replacement.push_str(&format_result(Ok(x / total * 100.0), env));
+                replacement.push_str("%)");
+            }
+        }
+        replacement.push('\n');
+    }
+    if !lines.ends_with('\n') {
+        replacement.pop();
+    }
+    let len = lines.len();
+    note.replace_range(line_start..line_start + len, &replacement);
+    let start = note[..line_start].chars().count();
+    set_selection(output, start..start + replacement.chars().count());
+}
+
+/// Follow each line the selection touches with a tab and the cell `sequence` computes for it,
+/// selecting the lines.
+///
+/// The number is read from a line's first cell, and any cells after it are replaced, so a column
+/// can be worked out again after it is edited. A blank line ends a sequence, leaving the next
+/// line to start a new one as the first did, with no difference and a sum of its own. A line
+/// that is not a number or expression ends one too, and has its error in place of a cell.
+fn sequence_column(
+    note: &mut String,
+    output: &mut TextEditOutput,
+    env: &Environment,
+    sequence: Sequence,
+) {
+    let Some(range) = selected_lines(note, output) else {
+        return;
+    };
+    let (lines, line_start) = (&note[range.clone()], range.start);
+    let mut previous: Option<Value> = None;
+    let mut replacement = String::new();
+    for line in lines.lines() {
+        let line = line.split_once('\t').map_or(line, |x| x.0);
+        replacement.push_str(line);
+        if line.trim().is_empty() {
+            previous = None;
+        } else {
+            let cell = match expr::eval_with(line, env) {
+                Ok(x) => {
+                    let cell = match sequence {
+                        Sequence::Differences => previous.replace(x).map(|before| x - before),
+                        Sequence::RunningSums => {
+                            let sum = previous.unwrap_or(0.0) + x;
+                            previous = Some(sum);
+                            Some(sum)
+                        }
+                    };
+                    cell.map(|x| format_result(Ok(x), env))
+                }
+                Err(error) => {
+                    previous = None;
+                    Some(error.to_string())
+                }
+            };
+            replacement.push('\t');
+            replacement.push_str(cell.as_deref().unwrap_or_default());
+        }
+        replacement.push('\n');
+    }
+    if !lines.ends_with('\n') {
+        replacement.pop();
+    }
+    let len = lines.len();
+    note.replace_range(line_start..line_start + len, &replacement);
+    let start = note[..line_start].chars().count();
+    set_selection(output, start..start + replacement.chars().count());
+}
+
+/// Starts each cell that [`total_grid`] fills in with a sum
+const SUM_CELL: &str = "= ";
+
+/// Add up the tab-separated grid in the lines the selection touches, selecting the sums below it.
+///
+/// Each row's sum goes in a cell after the widest row, so the sums line up however ragged the
+/// rows are, and a row below the grid holds each column's sum and then the grand total. Sums are
+/// written like `= 12`, and a column with nothing to add up gets an empty cell. Cells that are
+/// blank or not numbers or expressions are skipped, and sums from an earlier run are replaced, so
+/// a grid can be totalled again after it is edited.
+fn total_grid(note: &mut String, output: &mut TextEditOutput, env: &Environment) {
+    let Some(lines) = selected_lines(note, output) else {
+        return;
+    };
+    let is_sum = |x: &&str| x.starts_with(SUM_CELL);
+    let mut rows: Vec<Vec<&str>> = note[lines.clone()]
+        .lines()
+        .map(|x| x.split('\t').collect())
+        .collect();
+    if rows.len() > 1
+        && rows
+            .last()
+            .is_some_and(|x| x.iter().all(|x| x.is_empty() || is_sum(x)))
+    {
+        rows.pop();
+    }
+    for row in &mut rows {
+        if row.last().is_some_and(is_sum) {
+            row.pop();
+            while row.len() > 1 && row.last().is_some_and(|x| x.is_empty()) {
+                row.pop();
+            }
+        }
+    }
+    let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let mut columns: Vec<Option<Value>> = vec![None; width];
+    let mut total = None;
+    let mut replacement = String::new();
+    for row in &rows {
+        let mut sum = None;
+        for (cell, column) in row.iter().zip(&mut columns) {
+            if let Ok(x) = expr::eval_with(cell, env) {
+                sum = Some(sum.unwrap_or(0.0) + x);
+                *column = Some(column.unwrap_or(0.0) + x);
+            }
+        }
+        replacement.push_str(&row.join("\t"));
+        if let Some(sum) = sum {
+            replacement.push_str(&"\t".repeat(width - row.len() + 1));
+            replacement.push_str(SUM_CELL);
+            replacement.push_str(&format_result(Ok(sum), env));
+            total = Some(total.unwrap_or(0.0) + sum);
+        }
+        replacement.push('\n');
+    }
+    let sums: Vec<String> = (columns.into_iter().chain([total]))
+        .map(|x| {
+            x.map_or_else(String::new, |x| {
+                format!("{SUM_CELL}{}", format_result(Ok(x), env))
+            })
+        })
+        .collect();
+    let sums = sums.join("\t");
+    replacement.push_str(&sums);
+    note.replace_range(lines.clone(), &replacement);
+    let end = note[..lines.start + replacement.len()].chars().count();
+    set_selection(output, end - sums.chars().count()..end);
+}
+
+/// Byte range of the lines the selection touches, without the line break after the last, leaving
+/// out a line the selection only ends at the start of
+fn selected_lines(note: &str, output: &TextEditOutput) -> Option<Range<usize>> {
+    let range = output.cursor_range?.as_sorted_char_range();
+    let start = note.byte_index_from_char_index(range.start);
+    let mut end = note.byte_index_from_char_index(range.end);
+    if end > start && note[..end].ends_with('\n') {
+        end -= 1;
+    }
+    let lines = whole_lines(note, start, end);
+    let start = note[..start].rfind('\n').map_or(0, |x| x + 1);
+    Some(start..start + lines.len())
+}
+
+/// Take typed text that should wrap the selection rather than replace it: `(`, or a function
+/// name followed by `(` as pasted or entered through an input method
+fn take_wrapper(input: &mut egui::InputState) -> Option<String> {
+    let index = input.events.iter().position(|x| {
+        matches!(x, egui::Event::Text(text) if text.strip_suffix('(')
+            .is_some_and(|x| x.chars().all(|c| c.is_alphanumeric() || c == '_')))
+    })?;
+    let egui::Event::Text(text) = input.events.remove(index) else {
+        unreachable!()
+    };
+    Some(text[..text.len() - 1].to_owned())
+}
+
+/// Pasted `text` with its decimal commas replaced by points, if every comma in it is one.
+///
+/// A comma is a decimal comma when it has digits on both sides, is the only comma in its number,
+/// is not in the arguments of a function, and is not followed by exactly three digits unless it
+/// follows a lone `0`. So `3,14` and `0,125` become `3.14` and `0.125`, but text with `1,234`,
+/// which is as often a thousands separator, `1,234,567`, `max(1, 2)`, or `pow(2,3)` is left alone.
+/// Text that already has a `.` uses it as the decimal point, so it is left alone too.
+fn decimal_commas(text: &str) -> Option<String> {
+    if text.contains('.') || !text.contains(',') {
+        return None;
+    }
+    let chars: Vec<char> = text.chars().collect();
+    // Whether each parenthesis the text is in opens the arguments of a function
+    let mut calls = Vec::new();
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            '(' => calls.push(i > 0 && chars[i - 1].is_alphanumeric()),
+            ')' => {
+                calls.pop();
+            }
+            ',' if calls.contains(&true) => return None,
+            _ => {}
+        }
+    }
+    for i in (0..chars.len()).filter(|&i| chars[i] == ',') {
+        let before = chars[..i]
+            .iter()
+            .rev()
+            .take_while(|x| x.is_ascii_digit())
+            .count();
+        let after = chars[i + 1..]
+            .iter()
+            .take_while(|x| x.is_ascii_digit())
+            .count();
+        let grouped =
+            chars.get(i + 1 + after) == Some(&',') || (before < i && chars[i - before - 1] == ',');
+        let lone_zero = before == 1 && chars[i - 1] == '0';
+        if before == 0 || after == 0 || grouped || (after == 3 && !lone_zero) {
+            return None;
+        }
+    }
+    Some(text.replace(',', "."))
+}
+
+/// Wrap the selection in parentheses, after the function `name` if it is not empty.
+///
+/// Bare parentheses leave the cursor before them, so a function name can be typed next.
+fn wrap_selection(note: &mut String, output: &mut TextEditOutput, name: &str) {
+    if let Some(cursor) = output.cursor_range {
+        let range = cursor.as_sorted_char_range();
+        let wrapped = format!("{name}({})", note.char_range(range.clone()));
+        note.delete_char_range(range.clone());
+        note.insert_text(&wrapped, range.start);
+        let end = if name.is_empty() {
+            range.start
+        } else {
+            range.start + wrapped.chars().count()
+        };
+        set_selection(output, end..end);
+    }
+}
+
+/// Show the expression in the statement `text` with [`expr::Expression::simplify`] applied, if that
+/// changes it
+fn show_simplified(ui: &mut Ui, text: &str, env: &Environment) {
+    if let Ok(expression) = expr::parse_str(text, env) {
+        let read = expression.to_string();
+        let simplified = expression.simplify().to_string();
+        if simplified != read {
+            ui.label(format!("Simplifies to {simplified}"));
+        }
+    }
+}
+
+/// Show `node` and, indented below it, its operands, each of which can be collapsed
+fn show_tree(ui: &mut Ui, node: &expr::TreeNode, id: egui::Id) {
+    if node.children.is_empty() {
+        ui.label(&node.label);
+        return;
+    }
+    egui::CollapsingHeader::new(&node.label)
+        .id_source(id)
+        .default_open(true)
+        .show(ui, |ui| {
+            for (i, child) in node.children.iter().enumerate() {
+                show_tree(ui, child, id.with(i));
+            }
+        });
+}
+
+/// Whether `data` starts like a TrueType or OpenType font or font collection, which egui can load
+fn is_font(data: &[u8]) -> bool {
+    [b"\0\x01\0\0", b"OTTO", b"true", b"ttcf"]
+        .iter()
+        .any(|x| data.starts_with(*x))
+}
+
+/// Hash identifying the text of a line, for tracking which results are out of date
+fn line_hash(line: &str) -> u64 {
+    BuildHasherDefault::<DefaultHasher>::default().hash_one(line)
+}
+
+/// The line of `text` that the byte index `at` is on, without its line break
+fn line_around(text: &str, at: usize) -> &str {
+    whole_lines(text, at, at)
+}
+
+/// The lines of `text` that the byte range from `start` to `end` touches, without the line break
+/// after the last
+fn whole_lines(text: &str, start: usize, end: usize) -> &str {
+    let start = text[..start].rfind('\n').map_or(0, |x| x + 1);
+    let end = text[end..].find('\n').map_or(text.len(), |x| end + x);
+    &text[start..end]
+}
+
+/// Byte index in `line` of the result laid out by `template` that ends it, if there is one
+fn trailing_result(template: &str, line: &str) -> Option<usize> {
+    let (prefix, _) = template.split_once("{}")?;
+    line.match_indices(prefix)
+        .map(|(i, _)| i)
+        .find(|&i| existing_result(template, &line[i..]) == Some(line.len() - i))
+}
+
+/// Whether the result laid out by `template` from byte `start` to the end of `line` is the one its
+/// expression gives in `env`
+fn shows_result(
+    line: &str,
+    start: usize,
+    template: &str,
+    terminators: &[char],
+    env: &Environment,
+) -> bool {
+    let expression = line_expression(line, template, terminators, |x| evaluates(x, env));
+    let result = format_result(execute(expression, &mut env.clone()), env);
+    render_result(template, &result) == line[start..]
+}
+
+/// The expression that `line` gives a result for, as evaluated with the cursor at its end.
+///
+/// That is the text before any result inserted with `template`, after any label ended by one of
+/// the `terminators`, like `total:`, and without the leading words that keep it from being an
+/// expression, as [`expression_range`] picks it out, so `total: 2 + 3 = 5` gives `2 + 3`.
+fn line_expression<'a>(
+    line: &'a str,
+    template: &str,
+    terminators: &[char],
+    is_expression: impl Fn(&str) -> bool,
+) -> &'a str {
+    let before = &line[..trailing_result(template, line).unwrap_or(line.len())];
+    let cursor = before.chars().count();
+    let range = expression_range(before, cursor, cursor, terminators, is_expression);
+    &before[before.byte_index_from_char_index(range.start)
+        ..before.byte_index_from_char_index(range.end)]
+}
+
+/// Lay out the note in `font_id`, with the results on `stale_lines` dimmed and struck through, and
+/// with `sign_colors`, the others colored by [`sign_color`]
+fn note_layout(
+    ui: &Ui,
+    text: &str,
+    font_id: egui::FontId,
+    stale_lines: &HashSet<u64>,
+    template: &str,
+    sign_colors: bool,
+) -> egui::text::LayoutJob {
+    // The placeholder color is painted in the editor's own text color
+    let normal = egui::TextFormat::simple(font_id, egui::Color32::PLACEHOLDER);
+    let weak = ui.visuals().weak_text_color();
+    let dimmed = egui::TextFormat {
+        color: weak,
+        strikethrough: egui::Stroke::new(1.0, weak),
+        ..normal.clone()
+    };
+    let mut job = egui::text::LayoutJob::default();
+    for line in text.split_inclusive('\n') {
+        let content = line.strip_suffix('\n').unwrap_or(line);
+        let stale = !stale_lines.is_empty() && stale_lines.contains(&line_hash(content));
+        let start = if stale || sign_colors {
+            trailing_result(template, content)
+        } else {
+            None
+        };
+        let Some(start) = start else {
+            job.append(line, 0.0, normal.clone());
+            continue;
+        };
+        job.append(&line[..start], 0.0, normal.clone());
+        if stale {
+            job.append(&line[start..], 0.0, dimmed.clone());
+            continue;
+        }
+        // Only the number itself is colored, not the text around it in the template
+        let (prefix, suffix) = template.split_once("{}").unwrap_or_default();
+        let number = start + prefix.len()..content.len() - suffix.len();
+        let colored = sign_color(ui, &content[number.clone()]).map_or_else(
+            || normal.clone(),
+            |color| egui::TextFormat {
+                color,
+                ..normal.clone()
+            },
+        );
+        job.append(&line[start..number.start], 0.0, normal.clone());
+        job.append(&content[number.clone()], 0.0, colored);
+        job.append(&line[number.end..], 0.0, normal.clone());
+    }
+    if text.is_empty() {
+        job.append("", 0.0, normal);
+    }
+    job
+}
+
+/// Color of a result shown as `result`: green when positive, red when negative, and dimmed when
+/// zero. Errors, pairs, and `NaN`, which have no sign, get none.
+fn sign_color(ui: &Ui, result: &str) -> Option<egui::Color32> {
+    let x = result.parse::<f64>().ok().filter(|x| !x.is_nan())?;
+    let visuals = ui.visuals();
+    Some(if x < 0.0 {
+        visuals.error_fg_color
+    } else if x == 0.0 {
+        visuals.weak_text_color()
+    } else if visuals.dark_mode {
+        egui::Color32::LIGHT_GREEN
+    } else {
+        egui::Color32::DARK_GREEN
+    })
+}
+
+/// Byte range of the character in `text` at `pos`, or of the last one if `pos` is at or past its
+/// end, ignoring trailing whitespace
+fn error_range(text: &str, pos: usize) -> Range<usize> {
+    let text = text.trim_end();
+    let at = if pos < text.len() {
+        pos
+    } else {
+        text.char_indices().last().map_or(0, |(i, _)| i)
+    };
+    at..at + text[at..].chars().next().map_or(0, char::len_utf8)
+}
+
+/// Underline `error`, a byte range of the text laid out by `job`, in the error color
+fn mark_error(ui: &Ui, job: &mut egui::text::LayoutJob, error: Range<usize>) {
+    let color = ui.visuals().error_fg_color;
+    let mut sections = Vec::new();
+    for section in job.sections.drain(..) {
+        let range = section.byte_range.clone();
+        let (start, end) = (
+            error.start.clamp(range.start, range.end),
+            error.end.clamp(range.start, range.end),
+        );
+        for (byte_range, marked) in [
+            (range.start..start, false),
+            (start..end, true),
+            (end..range.end, false),
+        ] {
+            if !byte_range.is_empty() {
+                let mut format = section.format.clone();
+                if marked {
+                    format.color = color;
+                    format.underline = egui::Stroke::new(1.0, color);
+                }
+                sections.push(egui::text::LayoutSection {
+                    byte_range,
+                    format,
+                    ..section.clone()
+                });
+            }
+        }
+    }
+    job.sections = sections;
+}
+
+/// The note and the editor's selection as they are now
+fn snapshot(note: &str, output: &TextEditOutput) -> Snapshot {
+    Snapshot {
+        text: note.to_owned(),
+        selection: output.state.cursor.char_range(),
+    }
+}
+
+/// Select the given character range in the editor
+fn set_selection(output: &mut TextEditOutput, range: Range<usize>) {
+    output
+        .state
+        .cursor
+        .set_char_range(Some(egui::text::CCursorRange {
+            primary: egui::text::CCursor {
+                index: range.end,
+                prefer_next_row: true,
+            },
+            secondary: egui::text::CCursor {
+                index: range.start,
+                prefer_next_row: true,
+            },
+        }));
+}
+
+/// Text inserted into the note for the result of an evaluation
+fn format_result(result: expr::Result<Value>, env: &Environment) -> String {
+    match result {
+        Ok(x) => expr::format_value(x, env),
+        Err(x) => x.to_string(),
+    }
+}
+
+/// Text inserted into the note for both results of an expression with a `±`
+fn format_plus_minus(plus: Value, minus: Value, env: &Environment) -> String {
+    format!(
+        "{{{}, {}}}",
+        format_result(Ok(plus), env),
+        format_result(Ok(minus), env)
+    )
+}
+
+/// Lay out the rows of a table on the lines below its expression, with the variable's values
+/// right-aligned so the bars line up
+fn format_table(rows: &[expr::TableRow], env: &Environment) -> String {
+    let inputs: Vec<String> = rows.iter().map(|x| expr::format_value(x.0, env)).collect();
+    let width = inputs.iter().map(|x| x.chars().count()).max().unwrap_or(0);
+    let mut table = String::new();
+    for (x, row) in iter::zip(inputs, rows) {
+        table.push('\n');
+        table.push_str(&" ".repeat(width - x.chars().count()));
+        table.push_str(&x);
+        table.push_str(" | ");
+        table.push_str(&format_result(row.1.clone(), env));
+    }
+    table
 }
 
 impl eframe::App for NotesApp {
     fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
+        self.quick_bar(ctx);
+        self.scratch_panel(ctx);
         egui::CentralPanel::default().show(ctx, |ui| {
-            let eval = ui.input_mut(|x| {
-                x.consume_key(Modifiers::CTRL, egui::Key::Enter)
-                    || x.consume_key(Modifiers::SHIFT, egui::Key::Enter)
+            let eval = ui.input_mut(|x| self.take_eval(x));
+            let mut edit = ui.input_mut(|x| {
+                SYMBOL_SHORTCUTS
+                    .iter()
+                    .find(|(shortcut, _)| x.consume_shortcut(shortcut))
+                    .map(|&(_, symbol)| Edit::Insert(symbol))
+                    // Checked before duplicating, as Ctrl+D would also match it
+                    .or_else(|| x.consume_shortcut(&DATE_SHORTCUT).then_some(Edit::Date))
+                    // Checked first, as F3 would also match it
+                    .or_else(|| {
+                        x.consume_shortcut(&REEVALUATE_NEXT_SHORTCUT)
+                            .then_some(Edit::NextResult { evaluate: true })
+                    })
+                    .or_else(|| {
+                        x.consume_shortcut(&NEXT_RESULT_SHORTCUT)
+                            .then_some(Edit::NextResult { evaluate: false })
+                    })
+                    .or_else(|| {
+                        x.consume_shortcut(&DUPLICATE_SHORTCUT)
+                            .then_some(Edit::DuplicateLines)
+                    })
             });
-            self.settings_open ^= ui.button("Settings").clicked();
-            egui::ScrollArea::vertical().show(ui, |ui| {
-                ui.add_sized(ui.available_size(), |ui: &mut Ui| {
-                    let text_edit = egui::TextEdit::multiline(&mut self.notes_list[0]).font(
-                        if self.fixed_width {
-                            TextStyle::Monospace
-                        } else {
-                            TextStyle::Body
-                        },
-                    );
-                    let mut output = text_edit.show(ui);
-                    if eval {
-                        if let Some(cursor) = output.cursor_range {
-                            let p_idx = cursor.primary.ccursor.index;
-                            let s_idx = cursor.secondary.ccursor.index;
-                            let start = if p_idx == s_idx {
-                                self.notes_list[0].char_range(0..p_idx)
-                                    .rfind(|x| matches!(x, ':' | '=' | '\n'))
-                                    .map_or(0, |x| x + 1)
-                            } else {
-                                p_idx.min(s_idx)
-                            };
-                            let end_ch = p_idx.max(s_idx);
-                            let end_byte = self.notes_list[0].byte_index_from_char_index(end_ch);
-                            let text = &self.notes_list[0][start..end_byte];
-                            let result = evaluate(text);
-                            let insertion = format!(
-                                " = {}",
-                                match result {
-                                    Ok(x) => (x as f64).to_string(),
-                                    Err(x) => x.to_string(),
-                                }
-                            );
-                            output.state.cursor.set_char_range(Some(
-                                egui::text::CCursorRange {
-                                    primary: egui::text::CCursor {
-                                        index: end_ch + insertion.len(),
-                                        prefer_next_row: true,
-                                    },
-                                    secondary: egui::text::CCursor {
-                                        index: end_ch + insertion.len(),
-                                        prefer_next_row: true,
-                                    },
-                                },
-                            ));
-                            output.state.store(ctx, output.response.id);
-                            self.notes_list[0].insert_str(end_byte, &insertion);
+            if matches!(edit, Some(Edit::NextResult { .. })) {
+                // Only the focused editor shows its cursor, and has one to move on from
+                ui.memory_mut(|x| x.request_focus(egui::Id::new(EDITOR_ID)));
+            }
+            // The editor moves the cursor to the result before evaluating
+            let eval = eval.or_else(|| {
+                matches!(edit, Some(Edit::NextResult { evaluate: true }))
+                    .then_some(EvalScope::Expression)
+            });
+            // Left unconsumed, these undo and redo typing in the editor instead
+            let note = &self.notes_list[self.active];
+            if self.history.can_undo(note) && ui.input_mut(|x| x.consume_shortcut(&UNDO_SHORTCUT)) {
+                edit = edit.or(Some(Edit::Undo));
+            } else if self.history.can_redo(note)
+                && ui.input_mut(|x| x.consume_shortcut(&REDO_SHORTCUT))
+            {
+                edit = edit.or(Some(Edit::Redo));
+            }
+            let read_only = self.toolbar(ui);
+            let selected = self.selection.is_some_and(|x| x.primary != x.secondary);
+            let focused = ui.memory(|x| x.has_focus(egui::Id::new(EDITOR_ID)));
+            if selected && focused && !read_only {
+                edit = edit.or_else(|| ui.input_mut(take_wrapper).map(Edit::Wrap));
+            }
+            if focused && !read_only {
+                ui.input_mut(|x| {
+                    for event in &mut x.events {
+                        if let egui::Event::Paste(text) = event {
+                            text.retain(storable);
+                            if let Some(decimal) =
+                                decimal_commas(text).filter(|_| self.decimal_comma_paste)
+                            {
+                                *text = decimal;
+                            }
                         }
                     }
-                    output.response
+                });
+            }
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.add_sized(ui.available_size(), |ui: &mut Ui| {
+                    self.editor(ui, eval, edit, read_only)
                 })
             });
         });
+        if let Some(notice) = &self.notice {
+            let mut dismissed = false;
+            egui::Window::new("Notice")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(notice);
+                    dismissed = ui.button("OK").clicked();
+                });
+            if dismissed {
+                self.notice = None;
+            }
+        }
+        self.hint_window(ctx);
+        self.help_window(ctx);
+        self.tree_window(ctx);
+        let mut settings_open = self.settings_open;
         egui::Window::new("Settings")
-            .open(&mut self.settings_open)
-            .show(ctx, |ui| {
-                ui.checkbox(&mut self.fixed_width, "Enable monospace / fixed-width font");
-            });
+            .open(&mut settings_open)
+            .show(ctx, |ui| self.settings_ui(ui));
+        self.settings_open = settings_open;
     }
 
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
-        storage.set_string("notes_list", self.notes_list.clone().join("\x02")); // non-printable separator
+        self.save_notes(storage);
+        storage.set_string("active_note", self.active.to_string());
+        if let Some(backup) = &self.backup {
+            storage.set_string("notes_list.bak", backup.clone());
+        }
+        storage.set_string(
+            "read_only",
+            self.read_only
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        );
         storage.set_string("fixed_width", self.fixed_width.to_string());
+        storage.set_string("font", self.font.clone());
+        storage.set_string(
+            "degrees",
+            (self.angle_mode == AngleMode::Degrees).to_string(),
+        );
+        storage.set_string(
+            "natural_log",
+            (self.log_base == LogBase::Natural).to_string(),
+        );
+        storage.set_string(
+            "precision",
+            self.precision.map(|x| x.to_string()).unwrap_or_default(),
+        );
+        storage.set_string("programmer_mode", self.programmer_mode.to_string());
+        storage.set_string("ieee_division", self.ieee_division.to_string());
+        storage.set_string("lenient_trailing", self.lenient_trailing.to_string());
+        storage.set_string("strict_identifiers", self.strict_identifiers.to_string());
+        storage.set_string("result_template", self.result_template.clone());
+        storage.set_string("result_on_new_line", self.result_on_new_line.to_string());
+        storage.set_string("calculator_only", self.calculator_only.to_string());
+        storage.set_string("recompute_on_open", self.recompute_on_open.to_string());
+        storage.set_string("sign_colors", self.sign_colors.to_string());
+        storage.set_string("terminators", self.terminators.clone());
+        storage.set_string(
+            "shift_enter_evaluates",
+            self.shift_enter_evaluates.to_string(),
+        );
+        storage.set_string("decimal_comma_paste", self.decimal_comma_paste.to_string());
+        storage.set_string("scientific_above", self.scientific_above.to_string());
+        storage.set_string("scientific_below", self.scientific_below.to_string());
+        storage.set_string("extended_digits", self.extended_digits.to_string());
+        storage.set_string("summation_limit", self.summation_limit.to_string());
+        storage.set_string("integration_steps", self.integration_steps.to_string());
         storage.flush();
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use eframe::Storage as _;
+
+    /// Storage kept in memory, standing in for eframe's file
+    #[derive(Default)]
+    struct MemoryStorage(HashMap<String, String>);
+
+    impl eframe::Storage for MemoryStorage {
+        fn get_string(&self, key: &str) -> Option<String> {
+            self.0.get(key).cloned()
+        }
+
+        fn set_string(&mut self, key: &str, value: String) {
+            self.0.insert(key.to_owned(), value);
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    /// The notes loaded from `storage`, and the notice and backup loading left
+    fn load(storage: &MemoryStorage) -> (Vec<String>, Option<String>, Option<String>) {
+        let mut app = NotesApp::default();
+        app.load_notes(storage);
+        (app.notes_list, app.notice, app.backup)
+    }
+
+    /// Storage holding two saves, the first of `first` and the latest of `latest`
+    fn saved(first: &[&str], latest: &[&str]) -> MemoryStorage {
+        let mut storage = MemoryStorage::default();
+        let mut app = NotesApp::default();
+        for notes in [first, latest] {
+            app.notes_list = notes.iter().map(|&x| x.to_owned()).collect();
+            app.save_notes(&mut storage);
+        }
+        storage
+    }
+
+    #[test]
+    fn load_empty_notes_list() {
+        let mut storage = MemoryStorage::default();
+        storage.set_string("notes_list", String::new());
+        assert_eq!(load(&storage), (vec![String::new()], None, None));
+    }
+
+    #[test]
+    fn load_truncated_notes_list() {
+        let mut storage = saved(&["1+1", "old"], &["1+1 = 2", "new"]);
+        let data = storage.get_string("notes_list").unwrap();
+        storage.set_string("notes_list", data[..4].to_owned());
+        let (notes, notice, backup) = load(&storage);
+        assert_eq!(notes, ["1+1", "old"]);
+        assert!(notice.unwrap().contains("previous save was restored"));
+        assert_eq!(backup.as_deref(), Some(&data[..4]));
+    }
+
+    #[test]
+    fn load_garbage_notes_list() {
+        let mut storage = MemoryStorage::default();
+        storage.set_string("notes_list", "\u{0}\u{7f}garbage".to_owned());
+        storage.set_string("notes_list.checksum", checksum("something else"));
+        let (notes, notice, backup) = load(&storage);
+        assert_eq!(notes, [""]);
+        assert!(notice.unwrap().contains("could not be read"));
+        assert_eq!(backup.as_deref(), Some("\u{0}\u{7f}garbage"));
+    }
+
+    #[test]
+    fn load_notes_saved_with_control_characters() {
+        let storage = saved(&["a\u{c}b"], &["x\u{1b}y\tz", "2"]);
+        assert_eq!(
+            load(&storage),
+            (vec!["xy\tz".to_owned(), "2".to_owned()], None, None)
+        );
+    }
+
+    /// The expression `expression_range` picks out of `text` for a cursor at its `|`
+    fn picked(text: &str) -> String {
+        picked_by(&NotesApp::default(), text)
+    }
+
+    /// The expression `expression_range` picks out of `text` for a cursor at its `|`, with the
+    /// settings of `app`
+    fn picked_by(app: &NotesApp, text: &str) -> String {
+        let env = app.environment();
+        let cursor = text.find('|').unwrap();
+        let note = text.replace('|', "");
+        let cursor = note[..cursor].chars().count();
+        let range = expression_range(&note, cursor, cursor, &app.terminators(), |x| {
+            evaluates(x, &env)
+        });
+        note.char_range(range).to_owned()
+    }
+
+    #[test]
+    fn expression_picked_out_of_prose() {
+        assert_eq!(picked("the total is 2+2| apples"), "2+2");
+        assert_eq!(picked("the total is 2+|2 apples"), "2+2");
+        assert_eq!(picked("Rent: 1200 + 300|"), "1200 + 300");
+        assert_eq!(picked("we spent 40 * 3| on it. Then 2+2 more"), "40 * 3");
+        assert_eq!(picked("first line\n  3 * 4|"), "3 * 4");
+        assert_eq!(picked("x = 2 * 3|"), "x = 2 * 3");
+        assert_eq!(picked("a = b = 5|"), "a = b = 5");
+        assert_eq!(picked("2+2 = 4 and 3+|3 = 6"), "3+3");
+    }
+
+    /// Run one frame of `app`'s editor at `time`, evaluating what `eval` asks for and making `edit`
+    fn frame(
+        app: &mut NotesApp,
+        ctx: &egui::Context,
+        time: f64,
+        eval: Option<EvalScope>,
+        edit: Option<Edit>,
+    ) {
+        let read_only = app.read_only.contains(&app.active);
+        let input = egui::RawInput {
+            time: Some(time),
+            ..egui::RawInput::default()
+        };
+        let _ = ctx.run(input, |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                app.editor(ui, eval, edit, read_only);
+            });
+        });
+    }
+
+    /// Focus the editor and select the characters in `range`, from its start to its end
+    fn select(ctx: &egui::Context, range: Range<usize>) {
+        let id = egui::Id::new(EDITOR_ID);
+        let mut state = egui::TextEdit::load_state(ctx, id).unwrap_or_default();
+        state
+            .cursor
+            .set_char_range(Some(egui::text::CCursorRange::two(
+                egui::text::CCursor::new(range.start),
+                egui::text::CCursor::new(range.end),
+            )));
+        state.store(ctx, id);
+        ctx.memory_mut(|x| x.request_focus(id));
+    }
+
+    /// `app` with `note` as its only note, and a context to show its editor in
+    fn editing(note: &str) -> (NotesApp, egui::Context) {
+        let app = NotesApp {
+            notes_list: vec![note.to_owned()],
+            ..NotesApp::default()
+        };
+        (app, egui::Context::default())
+    }
+
+    /// Evaluate `scope` for the characters in `range` of the note as the editor does, waiting out
+    /// the highlight before the result is inserted
+    fn evaluate_at(app: &mut NotesApp, ctx: &egui::Context, range: Range<usize>, scope: EvalScope) {
+        frame(app, ctx, 0.0, None, None);
+        select(ctx, range);
+        frame(app, ctx, 1.0, Some(scope), None);
+        frame(app, ctx, 1.0 + HIGHLIGHT_SECONDS, None, None);
+    }
+
+    #[test]
+    fn evaluate_multiline_selection() {
+        let (mut app, ctx) = editing("1 +\n2 +\n3\nafter");
+        evaluate_at(&mut app, &ctx, 0..9, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "1 +\n2 +\n3 = 6\nafter");
+        let (mut app, ctx) = editing("1 +\n2 +\n3\n");
+        evaluate_at(&mut app, &ctx, 0..10, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "1 +\n2 +\n3 = 6\n");
+    }
+
+    #[test]
+    fn evaluate_sum_symbols_mid_note() {
+        let note = "Σ café ∑ notes\n∑(k, 1, 4, k) + ∏(k, 1, 3, k) apples\nlast ∏ line";
+        let (mut app, ctx) = editing(note);
+        let cursor = "Σ café ∑ notes\n∑(k, 1, 4, k) + ∏(k, 1, 3, k)"
+            .chars()
+            .count();
+        evaluate_at(&mut app, &ctx, cursor..cursor, EvalScope::Expression);
+        assert_eq!(
+            app.notes_list[0],
+            "Σ café ∑ notes\n∑(k, 1, 4, k) + ∏(k, 1, 3, k) = 16 apples\nlast ∏ line"
+        );
+    }
+
+    #[test]
+    fn evaluate_read_only_note() {
+        let (mut app, ctx) = editing("imported\n2 + 3\n");
+        app.read_only.insert(0);
+        evaluate_at(&mut app, &ctx, 14..14, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "imported\n2 + 3\n");
+        assert_eq!(app.scratch, "2 + 3 = 5\n");
+    }
+
+    #[test]
+    fn load_active_note() {
+        let mut storage = saved(&["a"], &["a", "b", "c"]);
+        storage.set_string("active_note", "2".to_owned());
+        let mut app = NotesApp::default();
+        app.load_notes(&storage);
+        assert_eq!(app.active, 2);
+        storage.set_string("active_note", "7".to_owned());
+        app.load_notes(&storage);
+        assert_eq!(app.active, 2);
+        storage.set_string("active_note", "-1".to_owned());
+        app.load_notes(&storage);
+        assert_eq!(app.active, 0);
+    }
+
+    #[test]
+    fn variables_stay_in_their_note() {
+        let (mut app, ctx) = editing("x = 5\nx");
+        app.notes_list.push("x".to_owned());
+        evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+        app.active = 1;
+        evaluate_at(&mut app, &ctx, 1..1, EvalScope::Expression);
+        let unknown = expr::Error::UnknownIdentifier("x".to_owned());
+        assert_eq!(app.notes_list[1], format!("x = {unknown}"));
+        app.active = 0;
+        evaluate_at(&mut app, &ctx, 15..15, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "x = 5 = 5\nx = 5");
+    }
+
+    #[test]
+    fn evaluate_greek_variables() {
+        let (mut app, ctx) = editing("θ = 3\nαβ = θ * 2\n2 αβ + θ");
+        for line in 1..=3 {
+            let note = &app.notes_list[0];
+            let end = note.split('\n').take(line).collect::<Vec<_>>().join("\n");
+            let cursor = end.chars().count();
+            evaluate_at(&mut app, &ctx, cursor..cursor, EvalScope::Expression);
+        }
+        assert_eq!(
+            app.notes_list[0],
+            "θ = 3 = 3\nαβ = θ * 2 = 6\n2 αβ + θ = 15"
+        );
+    }
+
+    #[test]
+    fn evaluate_embedded_expressions() {
+        let mut env = NotesApp::default().environment();
+        for (note, evaluated) in [
+            (
+                "The area is {{ 2 * 3^2 }}.",
+                "The area is {{ 2 * 3^2 = 18 }}.",
+            ),
+            ("{{1+1}}{{2+2}}", "{{1+1 = 2}}{{2+2 = 4}}"),
+            ("{{ {{ 2*3 }} }}", "{{ {{ 2*3 = 6 }} }}"),
+            ("{{{{1}}}}", "{{{{1 = 1}}}}"),
+            (
+                "{{ x = 4 }} then {{ x^2 }}",
+                "{{ x = 4 = 4 }} then {{ x^2 = 16 }}",
+            ),
+            ("{{ nope }} {{ 1/0 }} {{", "{{ nope }} {{ 1/0 }} {{"),
+            ("}} {{ 2 + 2 = 4 }}", "}} {{ 2 + 2 = 4 }}"),
+        ] {
+            assert_eq!(
+                evaluate_embedded(note, &mut env, DEFAULT_RESULT_TEMPLATE),
+                evaluated
+            );
+        }
+    }
+
+    #[test]
+    fn expression_around_cursor() {
+        assert_eq!(picked("2+|3+4"), "2+3+4");
+        assert_eq!(picked("|2+3+4"), "2+3+4");
+        assert_eq!(picked("2+3+4|"), "2+3+4");
+        assert_eq!(picked("1\n2 * |(3 + 4)\n5"), "2 * (3 + 4)");
+        assert_eq!(picked("2+|3+4 = 9"), "2+3+4");
+        assert_eq!(picked("x = 2|+3"), "x = 2+3");
+    }
+
+    #[test]
+    fn reevaluate_with_templates() {
+        for template in [" = {}", "={}", "\t= {}", " → {}", " [{}]", " ({} total)"] {
+            assert!(is_valid_template(template));
+            let (mut app, ctx) = editing("2 + 3");
+            app.result_template = template.to_owned();
+            let expected = |result| format!("2 + 3{}", template.replace("{}", result));
+            evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+            assert_eq!(app.notes_list[0], expected("5"));
+            evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+            assert_eq!(app.notes_list[0], expected("5"));
+            app.notes_list[0].replace_range(4..5, "4");
+            evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+            assert_eq!(app.notes_list[0], expected("6").replacen('3', "4", 1));
+        }
+    }
+
+    #[test]
+    fn results_on_new_line() {
+        for (new_line, evaluated) in [(false, "2 + 3 = 5\nnext"), (true, "2 + 3\n= 5\nnext")] {
+            let (mut app, ctx) = editing("2 + 3\nnext");
+            app.result_on_new_line = new_line;
+            evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+            assert_eq!(app.notes_list[0], evaluated);
+            evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+            assert_eq!(app.notes_list[0], evaluated);
+            app.notes_list[0].replace_range(4..5, "4");
+            evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+            assert_eq!(
+                app.notes_list[0],
+                evaluated.replacen('3', "4", 1).replace('5', "6")
+            );
+        }
+        let mut storage = MemoryStorage::default();
+        let mut app = NotesApp {
+            result_on_new_line: true,
+            ..NotesApp::default()
+        };
+        eframe::App::save(&mut app, &mut storage);
+        assert_eq!(
+            storage.get_string("result_on_new_line").as_deref(),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn evaluate_indented_lines() {
+        let note = "outline\n    2+3\n\t\tx = 4\n \t x * 2\nend";
+        let (mut app, ctx) = editing(note);
+        for line in 2..=4 {
+            let note = &app.notes_list[0];
+            let end = note.split('\n').take(line).collect::<Vec<_>>().join("\n");
+            let cursor = end.chars().count();
+            evaluate_at(&mut app, &ctx, cursor..cursor, EvalScope::Expression);
+        }
+        assert_eq!(
+            app.notes_list[0],
+            "outline\n    2+3 = 5\n\t\tx = 4 = 4\n \t x * 2 = 8\nend"
+        );
+        assert_eq!(picked("a\n    2+|3"), "2+3");
+    }
+
+    #[test]
+    fn rounded_display_keeps_exact_ans() {
+        let (mut app, ctx) = editing("pi\nans * 2\nans - 2 pi");
+        app.precision = Some(2);
+        for line in 1..=3 {
+            let note = &app.notes_list[0];
+            let end = note.split('\n').take(line).collect::<Vec<_>>().join("\n");
+            let cursor = end.chars().count();
+            evaluate_at(&mut app, &ctx, cursor..cursor, EvalScope::Expression);
+        }
+        assert_eq!(
+            app.notes_list[0],
+            "pi = 3.14\nans * 2 = 6.28\nans - 2 pi = 0.00"
+        );
+        assert!(app.variables[&0][ANS].abs() < 1e-30);
+    }
+
+    /// Storage that stops keeping what is written to it after a number of writes, as if the app had
+    /// died while saving
+    struct Interrupted<'a>(&'a mut MemoryStorage, usize);
+
+    impl eframe::Storage for Interrupted<'_> {
+        fn get_string(&self, key: &str) -> Option<String> {
+            self.0.get_string(key)
+        }
+
+        fn set_string(&mut self, key: &str, value: String) {
+            if let Some(writes) = self.1.checked_sub(1) {
+                self.1 = writes;
+                self.0.set_string(key, value);
+            }
+        }
+
+        fn flush(&mut self) {}
+    }
+
+    #[test]
+    fn load_after_interrupted_save() {
+        for writes in 0..=4 {
+            let mut storage = saved(&["a"], &["1+1 = 2", "old"]);
+            let mut app = NotesApp {
+                notes_list: vec!["1+1 = 2".to_owned(), "new".to_owned()],
+                ..NotesApp::default()
+            };
+            app.save_notes(&mut Interrupted(&mut storage, writes));
+            let (notes, _, _) = load(&storage);
+            let expected = if writes < 4 { "old" } else { "new" };
+            assert_eq!(notes, ["1+1 = 2", expected]);
+        }
+    }
+
+    /// The characters selected in the editor, from its start to its end
+    fn selection(ctx: &egui::Context) -> Range<usize> {
+        let state = egui::TextEdit::load_state(ctx, egui::Id::new(EDITOR_ID)).unwrap();
+        let [start, end] = state.cursor.char_range().unwrap().sorted();
+        start.index..end.index
+    }
+
+    #[test]
+    fn wrap_multibyte_selection() {
+        for (name, wrapped, cursor) in [("", "café (π·r²) ü", 5), ("sqrt", "café sqrt(π·r²) ü", 15)]
+        {
+            let (mut app, ctx) = editing("café π·r² ü");
+            frame(&mut app, &ctx, 0.0, None, None);
+            select(&ctx, 5..9);
+            frame(&mut app, &ctx, 1.0, None, Some(Edit::Wrap(name.to_owned())));
+            assert_eq!(app.notes_list[0], wrapped);
+            assert_eq!(selection(&ctx), cursor..cursor);
+        }
+    }
+
+    #[test]
+    fn evaluate_paragraph() {
+        let note = "Problem 1\n\nmass = 3\nspeed = 4\nmass * speed^2 / 2\n\nnext";
+        let (mut app, ctx) = editing(note);
+        evaluate_at(&mut app, &ctx, 14..14, EvalScope::Paragraph);
+        assert_eq!(
+            app.notes_list[0],
+            "Problem 1\n\nmass = 3\nspeed = 4\nmass * speed^2 / 2 = 24\n\nnext"
+        );
+        assert_eq!(app.variables[&0]["speed"], 4.0);
+    }
+
+    #[test]
+    fn load_each_storage_state() {
+        let fresh = MemoryStorage::default();
+        assert_eq!(load(&fresh), (vec![String::new()], None, None));
+
+        let mut legacy = MemoryStorage::default();
+        legacy.set_string("notes_text", "old\u{1b} note\n1+1".to_owned());
+        let mut app = NotesApp::default();
+        app.load_notes(&legacy);
+        assert_eq!(app.notes_list, ["old note\n1+1"]);
+        app.save_notes(&mut legacy);
+        legacy.set_string("notes_text", "stale".to_owned());
+        assert_eq!(
+            load(&legacy),
+            (vec!["old note\n1+1".to_owned()], None, None)
+        );
+
+        let new = saved(&["a"], &["b", "c"]);
+        assert_eq!(load(&new).0, ["b", "c"]);
+
+        let mut both = saved(&["a"], &["b", "c"]);
+        both.set_string("notes_text", "legacy".to_owned());
+        assert_eq!(load(&both).0, ["b", "c"]);
+    }
+
+    #[test]
+    fn extended_terminators() {
+        assert_eq!(picked("total: 2+|3"), "2+3");
+        assert_eq!(picked("a, 2+|3"), "2+3");
+        assert_eq!(picked("x = 1; 2+|3"), "2+3");
+        let app = NotesApp {
+            terminators: ":=,; ".to_owned(),
+            ..NotesApp::default()
+        };
+        assert_eq!(picked_by(&app, "total: 2+|3"), "2+3");
+        assert_eq!(picked_by(&app, "a, b, 2 + 3|"), "2 + 3");
+        assert_eq!(picked_by(&app, "x = 1; 4 * |5"), "4 * 5");
+        assert_eq!(picked_by(&app, "first line\n1+|1"), "1+1");
+    }
+
+    #[test]
+    fn duplicate_unicode_line() {
+        let (mut app, ctx) = editing("first\nπ·r² = café ✓\nlast");
+        frame(&mut app, &ctx, 0.0, None, None);
+        select(&ctx, 8..8);
+        frame(&mut app, &ctx, 1.0, None, Some(Edit::DuplicateLines));
+        assert_eq!(
+            app.notes_list[0],
+            "first\nπ·r² = café ✓\nπ·r² = café ✓\nlast"
+        );
+        assert_eq!(selection(&ctx), 22..22);
+    }
+
+    /// Input of a frame in which `key` is pressed with `modifiers`
+    fn key_press(key: Key, modifiers: Modifiers) -> egui::RawInput {
+        egui::RawInput {
+            modifiers,
+            events: vec![egui::Event::Key {
+                key,
+                physical_key: None,
+                pressed: true,
+                repeat: false,
+                modifiers,
+            }],
+            ..egui::RawInput::default()
+        }
+    }
+
+    /// What `app` evaluates when Enter is pressed with `modifiers`, and whether the press is left
+    /// for the editor
+    fn enter_pressed(app: &NotesApp, modifiers: Modifiers) -> (Option<EvalScope>, bool) {
+        let input = key_press(Key::Enter, modifiers);
+        let mut pressed = (None, false);
+        let _ = egui::Context::default().run(input, |ctx| {
+            pressed = ctx.input_mut(|x| (app.take_eval(x), x.key_pressed(Key::Enter)));
+        });
+        pressed
+    }
+
+    #[test]
+    fn shift_enter_setting() {
+        for evaluates in [true, false] {
+            let app = NotesApp {
+                shift_enter_evaluates: evaluates,
+                ..NotesApp::default()
+            };
+            let expression = Some(EvalScope::Expression);
+            assert_eq!(enter_pressed(&app, Modifiers::CTRL), (expression, false));
+            let shift = enter_pressed(&app, Modifiers::SHIFT);
+            assert_eq!(
+                shift,
+                if evaluates {
+                    (expression, false)
+                } else {
+                    (None, true)
+                }
+            );
+            assert_eq!(enter_pressed(&app, Modifiers::NONE), (None, true));
+            assert_eq!(
+                enter_pressed(&app, Modifiers::CTRL.plus(Modifiers::ALT)),
+                (Some(EvalScope::Paragraph), false)
+            );
+        }
+    }
+
+    #[test]
+    fn reevaluate_unchanged_line() {
+        let (mut app, ctx) = editing("2 + 3\nnext");
+        evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "2 + 3 = 5\nnext");
+        let changes = app.history.undo.len();
+        for cursor in [5, 2, 9] {
+            evaluate_at(&mut app, &ctx, cursor..cursor, EvalScope::Expression);
+            assert_eq!(app.notes_list[0], "2 + 3 = 5\nnext");
+            assert_eq!(app.history.undo.len(), changes);
+            assert_eq!(selection(&ctx), 9..9);
+        }
+        let (mut app, ctx) = editing("a = b = 5");
+        evaluate_at(&mut app, &ctx, 9..9, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "a = b = 5 = 5");
+        evaluate_at(&mut app, &ctx, 13..13, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "a = b = 5 = 5");
+    }
+
+    #[test]
+    fn undo_and_redo_inserted_result() {
+        let (mut app, ctx) = editing("2 + 3\nnext");
+        evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "2 + 3 = 5\nnext");
+        frame(&mut app, &ctx, 2.0, None, Some(Edit::Undo));
+        assert_eq!(app.notes_list[0], "2 + 3\nnext");
+        // The expression is selected while it is highlighted, before its result is inserted
+        assert_eq!(selection(&ctx), 0..5);
+        frame(&mut app, &ctx, 2.1, None, Some(Edit::Undo));
+        assert_eq!(app.notes_list[0], "2 + 3\nnext");
+        frame(&mut app, &ctx, 2.2, None, Some(Edit::Redo));
+        assert_eq!(app.notes_list[0], "2 + 3 = 5\nnext");
+        assert_eq!(selection(&ctx), 9..9);
+        frame(&mut app, &ctx, 2.3, None, Some(Edit::Redo));
+        assert_eq!(app.notes_list[0], "2 + 3 = 5\nnext");
+    }
+
+    #[test]
+    fn decimal_commas_in_paste() {
+        for (pasted, converted) in [
+            ("3,14", Some("3.14")),
+            ("0,125", Some("0.125")),
+            ("price 2,5 * 3,75", Some("price 2.5 * 3.75")),
+            ("(3,14 + 1) * 2", Some("(3.14 + 1) * 2")),
+            ("1,234", None),
+            ("1,234,567", None),
+            ("12,5 and 1,234", None),
+            ("3,14.5", None),
+            ("max(1, 2)", None),
+            ("pow(2,3)", None),
+            ("sqrt(2,25)", None),
+            ("a, b", None),
+            (",5", None),
+            ("no commas", None),
+        ] {
+            assert_eq!(decimal_commas(pasted).as_deref(), converted, "{pasted}");
+        }
+    }
+
+    #[test]
+    fn quick_bar_leaves_notes_alone() {
+        let (mut app, ctx) = editing("x = 5\nx");
+        evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+        let (notes, variables) = (app.notes_list.clone(), app.variables.clone());
+        app.quick_input = "x = x * 2".to_owned();
+        let input = key_press(QUICK_SHORTCUT.logical_key, QUICK_SHORTCUT.modifiers);
+        let _ = ctx.run(input, |ctx| app.quick_bar(ctx));
+        assert!(app.quick_open);
+        assert_eq!(app.quick_result, "10");
+        assert_eq!(app.notes_list, notes);
+        assert_eq!(app.variables, variables);
+    }
+
+    #[test]
+    fn evaluate_up_arrows_mid_note() {
+        let (mut app, ctx) = editing("powers ↑↑\n2↑3↑2 ✓\nend ↑");
+        let cursor = "powers ↑↑\n2↑3↑2".chars().count();
+        evaluate_at(&mut app, &ctx, cursor..cursor, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "powers ↑↑\n2↑3↑2 = 512 ✓\nend ↑");
+    }
+
+    /// `note` after `edit` is made to the characters in `range`, and what is then selected
+    fn edited(note: &str, range: Range<usize>, edit: Edit) -> (String, Range<usize>) {
+        let (mut app, ctx) = editing(note);
+        frame(&mut app, &ctx, 0.0, None, None);
+        select(&ctx, range);
+        frame(&mut app, &ctx, 1.0, None, Some(edit));
+        (app.notes_list.remove(0), selection(&ctx))
+    }
+
+    #[test]
+    fn total_column() {
+        let note = "Budget\n120\n\nrent?\n30.5\nafter";
+        let (total, selected) = edited(note, 8..20, Edit::Total { running: false });
+        assert_eq!(
+            total,
+            "Budget\n120\n\nrent?\n30.5\nTotal = 150.5 (1 line skipped)\nafter"
+        );
+        assert_eq!(total.char_range(selected), "Total = 150.5 (1 line skipped)");
+        let (running, _) = edited(note, 8..20, Edit::Total { running: true });
+        assert_eq!(
+            running,
+            "Budget\n120 → 120\n\nrent?\n30.5 → 150.5\nTotal = 150.5 (1 line skipped)\nafter"
+        );
+        let column = "Budget\n120 → 120\n\nrent?\n30".chars().count();
+        let (again, _) = edited(&running, 8..column, Edit::Total { running: true });
+        assert_eq!(
+            again.replacen("Total = 150.5 (1 line skipped)\n", "", 1),
+            running
+        );
+    }
+
+    #[test]
+    fn calculator_only_leaves_note_unchanged() {
+        let note = "café 2 + 3\nx = 4\n";
+        let (mut app, ctx) = editing(note);
+        app.calculator_only = true;
+        evaluate_at(&mut app, &ctx, 10..10, EvalScope::Expression);
+        assert_eq!(app.notes_list[0].as_bytes(), note.as_bytes());
+        assert_eq!(app.shown_result.as_deref(), Some("2 + 3 = 5"));
+        evaluate_at(&mut app, &ctx, 16..16, EvalScope::Expression);
+        assert_eq!(app.notes_list[0].as_bytes(), note.as_bytes());
+        assert_eq!(app.shown_result.as_deref(), Some("x = 4 = 4"));
+        let mut storage = MemoryStorage::default();
+        eframe::App::save(&mut app, &mut storage);
+        assert_eq!(
+            storage.get_string("calculator_only").as_deref(),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn total_ragged_grid() {
+        let grid = "1\t2\t3\n4\tapples\n\t5\t6\t7";
+        let note = format!("{grid}\nafter");
+        let (totalled, selected) = edited(&note, 0..grid.chars().count(), Edit::TotalGrid);
+        let expected =
+            "1\t2\t3\t\t= 6\n4\tapples\t\t\t= 4\n\t5\t6\t7\t= 18\n= 5\t= 7\t= 9\t= 7\t= 28";
+        assert_eq!(totalled, format!("{expected}\nafter"));
+        assert_eq!(totalled.char_range(selected), "= 5\t= 7\t= 9\t= 7\t= 28");
+        let (again, _) = edited(&totalled, 0..expected.chars().count(), Edit::TotalGrid);
+        assert_eq!(again, totalled);
+    }
+
+    #[test]
+    fn recompute_stale_results_on_open() {
+        let stale = "2 + 3 = 6\nx = 2 = 2\nx * 4 = 9\nprose = words";
+        let mut storage = saved(&[stale], &[stale]);
+        assert_eq!(NotesApp::restore(&storage).notes_list, [stale]);
+        storage.set_string("recompute_on_open", "true".to_owned());
+        let app = NotesApp::restore(&storage);
+        assert_eq!(
+            app.notes_list,
+            ["2 + 3 = 5\nx = 2 = 2\nx * 4 = 8\nprose = words"]
+        );
+        assert!(app.notice.unwrap().contains("2 lines"));
+    }
+
+    #[test]
+    fn evaluate_cell_chain() {
+        let (mut app, ctx) = editing("A1: 5\nA2: A1*2\nA3: A2+1\nA3\nB1: B2\nB2: B1\nB1");
+        evaluate_at(&mut app, &ctx, 26..26, EvalScope::Expression);
+        let cursor = app.notes_list[0].chars().count();
+        evaluate_at(&mut app, &ctx, cursor..cursor, EvalScope::Expression);
+        let circular = expr::Error::CircularReference("B1".to_owned());
+        assert_eq!(
+            app.notes_list[0],
+            format!("A1: 5\nA2: A1*2\nA3: A2+1\nA3 = 11\nB1: B2\nB2: B1\nB1 = {circular}")
+        );
+    }
+
+    #[test]
+    fn date_formats() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_mins(29_867_883);
+        assert_eq!(format_date_at(time, 0), "2026-10-15 14:03 +00:00");
+        assert_eq!(format_date_at(time, 7200), "2026-10-15 16:03 +02:00");
+        assert_eq!(format_date_at(time, -34_200), "2026-10-15 04:33 -09:30");
+        assert_eq!(
+            format_date_at(SystemTime::UNIX_EPOCH, -18_000),
+            "1969-12-31 19:00 -05:00"
+        );
+        let leap_day = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_251_199);
+        assert_eq!(format_date_at(leap_day, 0), "2024-02-29 23:59 +00:00");
+        assert_eq!(format_date_at(leap_day, 60), "2024-03-01 00:00 +00:01");
+    }
+
+    #[test]
+    fn date_shortcut_inserts_local_date() {
+        let (mut app, ctx) = editing("Log: \nmore");
+        frame(&mut app, &ctx, 0.0, None, None);
+        select(&ctx, 5..5);
+        let before = format_date(SystemTime::now());
+        frame(&mut app, &ctx, 1.0, None, Some(Edit::Date));
+        let after = format_date(SystemTime::now());
+        let note = &app.notes_list[0];
+        assert!(
+            [&before, &after]
+                .iter()
+                .any(|date| *note == format!("Log: {date}\nmore")),
+            "{note}"
+        );
+        let end = 5 + before.chars().count();
+        assert_eq!(selection(&ctx), end..end);
+        let offset = local_offset(SystemTime::now());
+        assert!(before.ends_with(&format!(
+            "{}{:02}:{:02}",
+            if offset < 0 { '-' } else { '+' },
+            offset.abs() / 3600,
+            offset.abs() % 3600 / 60
+        )));
+    }
+
+    #[test]
+    fn large_notes_warn_then_stop_saving() {
+        let mut storage = MemoryStorage::default();
+        let mut app = NotesApp {
+            notes_list: vec!["small".to_owned()],
+            ..NotesApp::default()
+        };
+        app.save_notes(&mut storage);
+        assert_eq!(app.notice, None);
+
+        let large = "x".repeat(SAVE_WARNING + 1);
+        app.notes_list = vec![large.clone()];
+        app.save_notes(&mut storage);
+        assert!(app.notice.take().is_some_and(|x| x.contains("nearing")));
+        assert_eq!(load(&storage).0, core::slice::from_ref(&large));
+        // The warning is given once, until the notes shrink again
+        app.save_notes(&mut storage);
+        assert_eq!(app.notice, None);
+
+        app.notes_list = vec![large.clone(), "x".repeat(SAVE_LIMIT - SAVE_WARNING)];
+        app.save_notes(&mut storage);
+        assert!(app.notice.take().is_some_and(|x| x.contains("too large")));
+        assert_eq!(load(&storage).0, [large]);
+
+        app.notes_list = vec!["small again".to_owned()];
+        app.save_notes(&mut storage);
+        assert_eq!(app.notice, None);
+        app.notes_list = vec!["x".repeat(SAVE_WARNING + 1)];
+        app.save_notes(&mut storage);
+        assert!(app.notice.is_some_and(|x| x.contains("nearing")));
+    }
+
+    /// The sections of `app`'s note laid out as the editor does that are not in the note's own
+    /// color, with their colors, and the colors for a positive, a negative, and a zero result
+    fn colored_results(
+        app: &NotesApp,
+        ctx: &egui::Context,
+    ) -> (Vec<(String, egui::Color32)>, [egui::Color32; 3]) {
+        let mut colored = (Vec::new(), [egui::Color32::PLACEHOLDER; 3]);
+        let _ = ctx.run(egui::RawInput::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                let text = &app.notes_list[0];
+                let job = note_layout(
+                    ui,
+                    text,
+                    egui::FontId::default(),
+                    &app.stale_lines,
+                    &app.result_template,
+                    app.sign_colors,
+                );
+                colored.0 = job
+                    .sections
+                    .iter()
+                    .filter(|x| x.format.color != egui::Color32::PLACEHOLDER)
+                    .map(|x| (text[x.byte_range.clone()].to_owned(), x.format.color))
+                    .collect();
+                colored.1 = ["1", "-1", "0"].map(|x| sign_color(ui, x).unwrap());
+            });
+        });
+        colored
+    }
+
+    #[test]
+    fn results_colored_by_sign() {
+        let note = "2 - 1 = 1\n1 - 3 = -2\n1 - 1 = 0\nsqrt(x) = <undefined variable `x`>\nnone";
+        let (mut app, ctx) = editing(note);
+        assert_eq!(colored_results(&app, &ctx).0, []);
+        app.sign_colors = true;
+        let (colored, [positive, negative, zero]) = colored_results(&app, &ctx);
+        assert_ne!(positive, negative);
+        assert_ne!(positive, zero);
+        assert_ne!(negative, zero);
+        assert_eq!(
+            colored,
+            [
+                ("1".to_owned(), positive),
+                ("-2".to_owned(), negative),
+                ("0".to_owned(), zero)
+            ]
+        );
+
+        let (mut app, ctx) = editing("5 - 3");
+        app.sign_colors = true;
+        evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "5 - 3 = 2");
+        assert_eq!(colored_results(&app, &ctx).0, [("2".to_owned(), positive)]);
+        app.notes_list[0] = "1 - 3".to_owned();
+        evaluate_at(&mut app, &ctx, 5..5, EvalScope::Expression);
+        assert_eq!(app.notes_list[0], "1 - 3 = -2");
+        assert_eq!(colored_results(&app, &ctx).0, [("-2".to_owned(), negative)]);
+    }
+
+    #[test]
+    fn step_through_unicode_results() {
+        let note = "café: 2·3 = 6\nπ → 1+1 = 2\nno result ✓\n√4 = 5";
+        let (mut app, ctx) = editing(note);
+        frame(&mut app, &ctx, 0.0, None, None);
+        select(&ctx, 0..0);
+        let next = || Some(Edit::NextResult { evaluate: false });
+        for (time, cursor) in [(1.0, 9), (1.1, 21), (1.2, 40), (1.3, 9)] {
+            frame(&mut app, &ctx, time, None, next());
+            assert_eq!(selection(&ctx), cursor..cursor);
+        }
+        // From the middle of a line, the next result is the one on it
+        select(&ctx, 16..16);
+        frame(&mut app, &ctx, 2.0, None, next());
+        assert_eq!(selection(&ctx), 21..21);
+        assert_eq!(app.notes_list[0], note);
+
+        select(&ctx, 30..30);
+        // As the app does for Shift+F3, the cursor moves to the result before it is evaluated
+        let reevaluate = Edit::NextResult { evaluate: true };
+        frame(
+            &mut app,
+            &ctx,
+            3.0,
+            Some(EvalScope::Expression),
+            Some(reevaluate),
+        );
+        frame(&mut app, &ctx, 4.0, None, None);
+        assert_eq!(
+            app.notes_list[0],
+            "café: 2·3 = 6\nπ → 1+1 = 2\nno result ✓\n√4 = 2"
+        );
+    }
+
+    #[test]
+    fn evaluate_long_line() {
+        let long = "1+".repeat(25_000) + "1";
+        let too_long = expr::Error::TooLong.to_string();
+        let started = Instant::now();
+        for range in [50_001..50_001, 0..50_001] {
+            let (mut app, ctx) = editing(&format!("{long}\nnext"));
+            evaluate_at(&mut app, &ctx, range, EvalScope::Expression);
+            assert!(app.notes_list[0] == format!("{long} = {too_long}\nnext"));
+        }
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn percent_of_total_column() {
+        let (percents, selected) = edited("20\n30\n50", 0..8, Edit::PercentOfTotal);
+        assert_eq!(percents, "20 (20%)\n30 (30%)\n50 (50%)");
+        assert_eq!(selected, 0..26);
+
+        let note = "Budget\n12.5\n0\nrent?\n37.5\n\n50.25 - 0.25";
+        let (percents, _) = edited(note, 7..37, Edit::PercentOfTotal);
+        assert_eq!(
+            percents,
+            "Budget\n12.5 (12.5%)\n0 (0%)\nrent?\n37.5 (37.5%)\n\n50.25 - 0.25 (50%)"
+        );
+        let sum: f64 = percents
+            .split('(')
+            .skip(1)
+            .map(|x| x.split_once('%').unwrap().0.parse::<f64>().unwrap())
+            .sum();
+        assert!((sum - 100.0).abs() < 1e-9);
+        // Working it out again replaces the percentages rather than adding more
+        let again = edited(&percents, 7..percents.chars().count(), Edit::PercentOfTotal).0;
+        assert_eq!(again, percents);
+
+        let (zeros, _) = edited("0\n0", 0..3, Edit::PercentOfTotal);
+        let by_zero = expr::Error::DivByZero.to_string();
+        assert_eq!(zeros, format!("0 ({by_zero})\n0 ({by_zero})"));
+    }
+
+    #[test]
+    fn expand_title_and_date() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_hours(494_000);
+        let date = format_date(time);
+        let note = "\n## Trip budget \nOn {{date}}: {{ title }}\n{{2 + 3}}, {{name}}, {{title";
+        assert_eq!(
+            expand_placeholders(note, time),
+            format!(
+                "\n## Trip budget \nOn {date}: Trip budget\n{{{{2 + 3}}}}, {{{{name}}}}, {{{{title"
+            )
+        );
+        assert_eq!(expand_placeholders("{{title}}", time), "{{title}}");
+        assert_eq!(
+            expand_placeholders("  \n{{date}}{{date}}", time),
+            format!("  \n{date}{date}")
+        );
+        assert_eq!(expand_placeholders("No pairs", time), "No pairs");
+    }
+
+    #[test]
+    fn sequence_boundaries() {
+        let sums = Edit::Sequence(Sequence::RunningSums);
+        let diffs = || Edit::Sequence(Sequence::Differences);
+        assert_eq!(edited("1\n2\n3", 0..5, sums).0, "1\t1\n2\t3\n3\t6");
+        assert_eq!(edited("1\n3\n6", 0..5, diffs()).0, "1\t\n3\t2\n6\t3");
+
+        let note = "1\n2\n\n3\n4\nn/a\n5\n7";
+        let unknown = expr::Error::UnknownIdentifier("n".to_owned());
+        let (sums, selected) = edited(note, 0..17, Edit::Sequence(Sequence::RunningSums));
+        assert_eq!(
+            sums,
+            format!("1\t1\n2\t3\n\n3\t3\n4\t7\nn/a\t{unknown}\n5\t5\n7\t12")
+        );
+        assert_eq!(selected, 0..sums.chars().count());
+        let (differences, _) = edited(note, 0..17, diffs());
+        assert_eq!(
+            differences,
+            format!("1\t\n2\t1\n\n3\t\n4\t1\nn/a\t{unknown}\n5\t\n7\t2")
+        );
+        // Working a column out again replaces its cells
+        assert_eq!(
+            edited(&sums, 0..sums.chars().count(), diffs()).0,
+            differences
+        );
+    }
+
+    #[test]
+    fn line_expression_rules() {
+        let env = Environment::default();
+        let terminators: Vec<char> = DEFAULT_TERMINATORS.chars().collect();
+        let pick =
+            |line, template| line_expression(line, template, &terminators, |x| evaluates(x, &env));
+        for (line, expression) in [
+            ("2 + 3", "2 + 3"),
+            ("2 + 3 = 5", "2 + 3"),
+            ("total: 2 + 3", "2 + 3"),
+            ("total: 2 + 3 = 5", "2 + 3"),
+            ("  total: 2 + 3 = 5", "2 + 3"),
+            ("x = 2 + 3 = 5", "x = 2 + 3"),
+            ("total: x = 2 + 3 = 5", "x = 2 + 3"),
+            ("the total is 2 + 3 = 5", "2 + 3"),
+            ("2 + 3 = <division by zero>", "2 + 3"),
+            ("a: b: 4 * 2 = 8", "4 * 2"),
+            ("", ""),
+        ] {
+            assert_eq!(pick(line, DEFAULT_RESULT_TEMPLATE), expression, "{line}");
+        }
+        assert_eq!(pick("total: 2 + 3 → 5", " → {}"), "2 + 3");
+        assert_eq!(pick("total: 2 + 3 = 5", " → {}"), "5");
+    }
+
+    #[test]
+    fn table_layout() {
+        let env = Environment::default();
+        let rows = expr::table("table(x, -1, 10, 3, 1/x)", &env)
+            .unwrap()
+            .unwrap();
+        let by_zero = expr::Error::DivByZero.to_string();
+        assert_eq!(
+            format_table(&rows, &env),
+            "\n-1 | -1\n 2 | 0.5\n 5 | 0.2\n 8 | 0.125"
+        );
+        let rows = expr::table("table(x, -2, 10, 4, 1/x)", &env)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            format_table(&rows, &env),
+            "\n-2 | -0.5\n 2 | 0.5\n 6 | 0.16666666666666666\n10 | 0.1"
+        );
+        let rows = expr::table("table(x, 0, 0, 1/x)", &env).unwrap().unwrap();
+        assert_eq!(format_table(&rows, &env), format!("\n0 | {by_zero}"));
+        assert_eq!(format_table(&[], &env), "");
+    }
+
+    #[test]
+    fn stored_limits_are_clamped() {
+        let restored = |summation: &str, integration: &str| {
+            let mut storage = MemoryStorage::default();
+            storage.set_string("summation_limit", summation.to_owned());
+            storage.set_string("integration_steps", integration.to_owned());
+            let app = NotesApp::restore(&storage);
+            (app.summation_limit, app.integration_steps)
+        };
+        assert_eq!(restored("500", "40"), (500, 40));
+        assert_eq!(
+            restored("0", "1"),
+            (
+                *expr::SUMMATION_LIMITS.start(),
+                *expr::INTEGRATION_STEPS.start()
+            )
+        );
+        assert_eq!(
+            restored("4000000000", "4000000000"),
+            (
+                *expr::SUMMATION_LIMITS.end(),
+                *expr::INTEGRATION_STEPS.end()
+            )
+        );
+        let defaults = (
+            expr::DEFAULT_SUMMATION_LIMIT,
+            expr::DEFAULT_INTEGRATION_STEPS,
+        );
+        assert_eq!(restored("lots", "-5"), defaults);
+        let app = NotesApp::restore(&MemoryStorage::default());
+        assert_eq!((app.summation_limit, app.integration_steps), defaults);
+    }
+}